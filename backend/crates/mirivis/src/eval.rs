@@ -1,28 +1,313 @@
+use std::{
+  cell::{Cell, RefCell},
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+  env, mem,
+  num::NonZeroU64,
+  path::PathBuf,
+  time::{Duration, Instant},
+};
+
 use anyhow::{anyhow, Context, Result};
+use either::Either;
 use flowistry::{
   cached::Cache,
   indexed::impls::LocationDomain,
   mir::utils::SpanExt,
   source_map::{Range, Spanner},
 };
-use miri::{Evaluator, InterpCx, InterpResult, LocalValue, Machine, MiriConfig};
-use rustc_hir::def_id::LocalDefId;
+use miri::{
+  AllocId, Evaluator, InterpCx, InterpError, InterpErrorInfo, InterpResult, LocalValue,
+  MPlaceTy, Machine, MiriConfig, Operand, Pointer, Provenance, TerminationInfo,
+};
+use rustc_hir::{def_id::LocalDefId, ItemKind};
 use rustc_middle::{
-  mir::{Body, ClearCrossCrate, LocalInfo, Location},
-  ty::TyCtxt,
+  mir::{
+    self, Body, ClearCrossCrate, Local, LocalInfo, Location, Terminator, TerminatorKind,
+  },
+  ty::{layout::LayoutOf, TyCtxt},
+};
+use rustc_span::{
+  def_id::LOCAL_CRATE, source_map::SourceMap, FileName, SourceFile, Span, Symbol,
 };
-use rustc_span::Span;
+use rustc_target::abi::Size;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::{mvalue::MValue, TypeDefIds};
+use crate::{
+  mvalue::{MAllocation, MAllocationKind, MBytes, MHeap, MValue, ReadLimits},
+  scope::TraceScope,
+  MirivisConfig, TypeDefIds,
+};
 
 #[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
 #[ts(export)]
 pub struct MFrame {
+  /// Unique for each call, so recursive calls of the same function can be
+  /// told apart across steps.
+  pub id: u64,
+  /// Position of the frame among the traced frames, from the outermost.
+  pub depth: usize,
+  pub name: String,
+  pub ranges: Vec<MRange>,
+  pub locals: Vec<MLocal>,
+}
+
+/// A span of source code. Lines and columns are 1-indexed, columns count
+/// characters, and the end column is exclusive.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone)]
+#[ts(export)]
+pub struct MRange {
+  /// Path of the source file, relative to the crate root.
+  pub file: String,
+  /// Character offsets into the file.
+  pub char_start: usize,
+  pub char_end: usize,
+  pub start_line: usize,
+  pub start_col: usize,
+  pub end_line: usize,
+  pub end_col: usize,
+}
+
+impl MRange {
+  pub(crate) fn from_span(span: Span, source_map: &SourceMap) -> Option<Self> {
+    let range = Range::from_span(span, source_map).ok()?;
+    let start = source_map.lookup_char_pos(span.lo());
+    let end = source_map.lookup_char_pos(span.hi());
+    Some(MRange {
+      file: relative_path(&start.file.name),
+      char_start: range.char_start,
+      char_end: range.char_end,
+      start_line: start.line,
+      start_col: start.col.0 + 1,
+      end_line: end.line,
+      end_col: end.col.0 + 1,
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MSourceFile {
+  /// Path relative to the crate root, as used in [`MRange::file`].
+  pub path: String,
+  pub contents: String,
+}
+
+/// Foreign functions that miri implements by allocating memory.
+const ALLOCATORS: &[&str] = &[
+  "__rust_alloc",
+  "__rust_alloc_zeroed",
+  "__rust_realloc",
+  "malloc",
+  "calloc",
+  "realloc",
+];
+
+/// Crates that allocate on behalf of their callers.
+const ALLOCATING_CRATES: &[&str] = &["alloc", "core", "hashbrown"];
+
+/// Cargo passes paths relative to the workspace root, and other build
+/// setups may pass absolute paths, so both are resolved before being made
+/// relative to the root of the traced crate.
+fn relative_path(name: &FileName) -> String {
+  let name = name.prefer_local().to_string();
+  let path = match env::current_dir() {
+    Ok(cwd) => cwd.join(&name),
+    Err(_) => PathBuf::from(&name),
+  };
+  let root = env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+  match root
+    .as_deref()
+    .and_then(|root| path.strip_prefix(root).ok())
+  {
+    Some(path) => path.display().to_string(),
+    None => name,
+  }
+}
+
+/// Whether `file` is source code of the traced crate, rather than of a
+/// dependency or generated by the compiler, like `<crate attribute>`.
+pub(crate) fn is_user_file(file: &SourceFile) -> bool {
+  file.cnum == LOCAL_CRATE && matches!(file.name, FileName::Real(_))
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MLocal {
   pub name: String,
-  pub ranges: Vec<(usize, usize)>,
-  pub locals: Vec<(String, MValue)>,
+  pub value: MValue,
+  /// The allocation backing this local, if its address has been taken.
+  pub alloc_id: Option<u64>,
+  /// Raw contents of the local, if byte views are enabled.
+  pub bytes: Option<MBytes>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MStep {
+  pub reason: MStepReason,
+  /// Frames of traced functions, from the outermost call to the innermost.
+  pub stack: Vec<MFrame>,
+  pub heap: MHeap,
+  /// Text written by the program since the previous step.
+  pub stdout: String,
+  pub stderr: String,
+}
+
+/// Why a step was emitted, relative to the previous point in user code.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MStepReason {
+  /// A MIR statement or terminator was executed on the same line.
+  Statement,
+  /// Execution moved to another source line in the same frame.
+  Line,
+  /// A traced function was entered.
+  Call,
+  /// A traced function returned to its caller.
+  Return,
+}
+
+/// Which points of execution are emitted as steps.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+  /// After every MIR statement or terminator in user code.
+  Statement,
+  /// Once per source line, when execution moves to another line.
+  Line,
+  /// Only when a traced function is entered or returns.
+  Call,
+}
+
+impl Default for Granularity {
+  fn default() -> Self {
+    Granularity::Line
+  }
+}
+
+/// Where the innermost frame is executing, if it is in user code.
+#[derive(Clone, Copy, PartialEq)]
+struct Position {
+  frame_id: u64,
+  line: usize,
+}
+
+/// How the traced program stopped.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[serde(tag = "type", content = "value")]
+#[ts(export)]
+pub enum MTermination {
+  /// The program returned from `main` or called `std::process::exit`.
+  Exit { code: i64 },
+  /// A panic unwound out of `main`. `stack` is the traced frames at the point
+  /// of the panic, and `ranges` is the code that panicked.
+  Panic {
+    message: String,
+    ranges: Vec<MRange>,
+    stack: Vec<MFrame>,
+  },
+  UndefinedBehavior {
+    message: String,
+    ranges: Vec<MRange>,
+  },
+  /// The program ran out of memory or stack space.
+  ResourceExhaustion { message: String },
+  /// Any other interpreter error, e.g. an operation Miri does not support.
+  Error { message: String },
+  /// Tracing stopped early because the program exceeded its [`Budget`].
+  LimitReached { limit: MLimit },
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MLimit {
+  Steps,
+  Frames,
+  Time,
+}
+
+/// Bounds on how long a program is traced, so that e.g. an infinite loop
+/// still produces a partial trace.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Budget {
+  /// Maximum number of MIR statements and terminators to execute.
+  pub max_steps: u64,
+  /// Maximum number of steps in the trace.
+  pub max_frames: usize,
+  /// Maximum wall-clock time to spend tracing, in seconds.
+  pub time_limit_secs: u64,
+}
+
+impl Default for Budget {
+  fn default() -> Self {
+    Budget {
+      max_steps: 10_000_000,
+      max_frames: 10_000,
+      time_limit_secs: 30,
+    }
+  }
+}
+
+/// The traced frames when the program most recently started panicking.
+struct PanicState {
+  stack: Vec<MFrame>,
+  /// Where the panic message starts in the program's stderr.
+  stderr_start: usize,
+}
+
+/// Extracts the message from the output of the default panic hook, i.e.
+/// `thread 'main' panicked at '<message>', src/main.rs:2:5`.
+fn panic_message(stderr: &str) -> String {
+  let report = stderr
+    .split("\nnote:")
+    .next()
+    .unwrap_or_default()
+    .trim_end();
+  report
+    .split_once("panicked at '")
+    .and_then(|(_, rest)| rest.rsplit_once("', "))
+    .map(|(message, _)| message.to_string())
+    .unwrap_or_else(|| report.to_string())
+}
+
+/// Everything the interpreted program has written to one output stream.
+#[derive(Default)]
+struct OutputStream {
+  bytes: Vec<u8>,
+  emitted: usize,
+}
+
+impl OutputStream {
+  /// Returns the text written since the last call.
+  fn take_new(&mut self) -> String {
+    let new = String::from_utf8_lossy(&self.bytes[self.emitted ..]).into_owned();
+    self.emitted = self.bytes.len();
+    new
+  }
+
+  fn all(&self) -> String {
+    String::from_utf8_lossy(&self.bytes).into_owned()
+  }
+}
+
+pub(crate) type Frame<'mir, 'tcx> =
+  miri::Frame<'mir, 'tcx, miri::Tag, miri::FrameData<'tcx>>;
+
+fn is_user_local(body: &Body, local: Local) -> bool {
+  matches!(
+    body.local_decls[local].local_info,
+    Some(box LocalInfo::User(ClearCrossCrate::Set(_)))
+  )
+}
+
+fn local_alloc_id(value: LocalValue<miri::Tag>) -> Option<AllocId> {
+  match value {
+    LocalValue::Live(Operand::Indirect(mplace)) => {
+      mplace.ptr.into_parts().0.and_then(|tag| tag.get_alloc_id())
+    }
+    _ => None,
+  }
 }
 
 pub struct VisEvaluator<'hir, 'mir, 'tcx> {
@@ -30,30 +315,182 @@ pub struct VisEvaluator<'hir, 'mir, 'tcx> {
   pub(super) ecx: InterpCx<'mir, 'tcx, Evaluator<'mir, 'tcx>>,
   pub(super) spanners: Cache<LocalDefId, Spanner<'hir, 'tcx>>,
   pub(super) type_def_ids: TypeDefIds,
+  pub(super) limits: ReadLimits,
+  pub(super) show_bytes: bool,
+  pub(super) depth: Cell<usize>,
+  /// The contents of each allocation read in this step, with the offset
+  /// they were read at.
+  pub(super) heap: RefCell<BTreeMap<AllocId, (Size, MValue)>>,
+  pub(super) visited: RefCell<HashSet<AllocId>>,
+  /// Pointers into allocations that have not been read yet.
+  pub(super) pending: RefCell<BTreeMap<(AllocId, Size), MPlaceTy<'tcx, miri::Tag>>>,
+  /// Where each allocation was last read from and at which offset, so it
+  /// can still be read once nothing points to it.
+  pub(super) alloc_places: RefCell<HashMap<AllocId, (Size, MPlaceTy<'tcx, miri::Tag>)>>,
+  /// Heap allocations made on behalf of user code, which are shown until
+  /// they are freed even if they have leaked.
+  user_allocs: RefCell<BTreeSet<AllocId>>,
+  /// The buffer of each `Vec` when it was last read, keyed by the location
+  /// of the `Vec`, to detect reallocations.
+  pub(super) vec_buffers: RefCell<HashMap<(AllocId, Size), AllocId>>,
+  pub(super) warnings: RefCell<Vec<String>>,
+  /// Statics defined in the traced crate, which are always shown in the heap.
+  statics: Vec<LocalDefId>,
+  stdout: OutputStream,
+  stderr: OutputStream,
+  /// Where `main` writes its exit code.
+  ret_place: MPlaceTy<'tcx, miri::Tag>,
+  panic: Option<PanicState>,
+  budget: Budget,
+  granularity: Granularity,
+  scope: TraceScope,
+  /// The last position in user code before the current step.
+  last_position: Option<Position>,
+  steps_taken: u64,
+  started: Instant,
+  limit_reached: Option<MLimit>,
+  /// The id of each frame on the miri stack.
+  frame_ids: Vec<u64>,
+  next_frame_id: u64,
 }
 
 impl<'hir, 'mir, 'tcx> VisEvaluator<'hir, 'mir, 'tcx>
 where
   'tcx: 'hir,
 {
-  pub fn new(tcx: TyCtxt<'tcx>, type_def_ids: TypeDefIds) -> Result<Self> {
+  pub fn new(
+    tcx: TyCtxt<'tcx>,
+    mut type_def_ids: TypeDefIds,
+    config: &MirivisConfig,
+  ) -> Result<Self> {
     let (main_id, entry_fn_type) = tcx
       .entry_fn(())
       .context("no main or start function found")?;
-    let (ecx, _) = miri::create_ecx(tcx, main_id, entry_fn_type, &MiriConfig {
+    let (ecx, ret_place) = miri::create_ecx(tcx, main_id, entry_fn_type, &MiriConfig {
       mute_stdout_stderr: true,
       ..Default::default()
     })
     .map_err(|e| anyhow!("{e}"))?;
 
+    // Only statics written by the author, not those generated by macros like
+    // the `#[thread_local]` key of `thread_local!`, which cannot be read as
+    // global memory.
+    let hir = tcx.hir();
+    let statics = hir
+      .items()
+      .map(|item_id| hir.item(item_id))
+      .filter(|item| matches!(item.kind, ItemKind::Static(..)))
+      .filter(|item| !item.span.from_expansion())
+      .map(|item| item.def_id)
+      .filter(|def_id| !tcx.is_thread_local_static(def_id.to_def_id()))
+      .collect();
+
+    let warnings = mem::take(&mut type_def_ids.unresolved);
+
     Ok(VisEvaluator {
       tcx,
       ecx,
       type_def_ids,
+      limits: config.limits.clone(),
+      show_bytes: config.show_bytes,
+      depth: Cell::default(),
       spanners: Cache::default(),
+      heap: RefCell::default(),
+      visited: RefCell::default(),
+      pending: RefCell::default(),
+      alloc_places: RefCell::default(),
+      user_allocs: RefCell::default(),
+      vec_buffers: RefCell::default(),
+      warnings: RefCell::new(warnings),
+      statics,
+      stdout: OutputStream::default(),
+      stderr: OutputStream::default(),
+      ret_place,
+      panic: None,
+      budget: config.budget.clone(),
+      granularity: config.granularity,
+      scope: TraceScope::new(tcx),
+      last_position: None,
+      steps_taken: 0,
+      started: Instant::now(),
+      limit_reached: None,
+      frame_ids: Vec::new(),
+      next_frame_id: 0,
     })
   }
 
+  pub(super) fn warn(&self, warning: String) {
+    let mut warnings = self.warnings.borrow_mut();
+    if !warnings.contains(&warning) {
+      warnings.push(warning);
+    }
+  }
+
+  /// Reports an error from reading the program's state. These are
+  /// limitations of mirivis rather than failures of the program, so they do
+  /// not end the trace.
+  pub(super) fn warn_read_error(&self, context: &str, error: InterpErrorInfo<'tcx>) {
+    self.warn(format!("{context}: {}", error.into_kind()));
+  }
+
+  pub fn take_warnings(&self) -> Vec<String> {
+    self.warnings.take()
+  }
+
+  /// Returns everything the program has written to stdout and stderr.
+  pub fn output(&self) -> (String, String) {
+    (self.stdout.all(), self.stderr.all())
+  }
+
+  /// Returns the name and arguments of the foreign function, e.g. a libc
+  /// function or an allocator shim, that is about to be called.
+  fn foreign_call(&self) -> Option<(Symbol, Vec<mir::Operand<'tcx>>)> {
+    let frame = Machine::stack(&self.ecx).last()?;
+    match frame.current_loc().map(|loc| frame.body.stmt_at(loc)) {
+      Ok(Either::Right(Terminator {
+        kind: TerminatorKind::Call { func, args, .. },
+        ..
+      })) => {
+        let (def_id, _) = func.const_fn_def()?;
+        self
+          .tcx
+          .is_foreign_item(def_id)
+          .then(|| (self.tcx.item_name(def_id), args.clone()))
+      }
+      _ => None,
+    }
+  }
+
+  /// Records the bytes of a `write` to stdout or stderr that is about to be
+  /// executed. Miri itself is muted, since our stdout carries the trace.
+  fn capture_output(&mut self) -> InterpResult<'tcx> {
+    let args = match self.foreign_call() {
+      Some((name, args)) if name.as_str() == "write" => args,
+      _ => return Ok(()),
+    };
+
+    let fd = self.ecx.eval_operand(&args[0], None)?;
+    let fd = self.ecx.read_scalar(&fd)?.check_init()?.to_i32()?;
+    if fd != 1 && fd != 2 {
+      return Ok(());
+    }
+
+    let buf = self.ecx.eval_operand(&args[1], None)?;
+    let buf = self.ecx.read_pointer(&buf)?;
+    let len = self.ecx.eval_operand(&args[2], None)?;
+    let len = self.read_usize(&len)?;
+    let u8_layout = self.ecx.layout_of(self.tcx.types.u8)?;
+    let bytes = self.read_u8s(&MPlaceTy::from_aligned_ptr(buf, u8_layout), len)?;
+
+    let stream = if fd == 1 {
+      &mut self.stdout
+    } else {
+      &mut self.stderr
+    };
+    stream.bytes.extend(bytes);
+    Ok(())
+  }
+
   fn spanner<'a>(
     &'a self,
     def_id: LocalDefId,
@@ -69,19 +506,90 @@ where
 
   fn build_frame(
     &self,
-    frame: &miri::Frame<'mir, 'tcx, miri::Tag, miri::FrameData<'tcx>>,
+    frame: &Frame<'mir, 'tcx>,
     def_id: LocalDefId,
+    id: u64,
+    depth: usize,
     current_loc: &Option<Result<Location, Span>>,
-  ) -> InterpResult<'tcx, MFrame> {
+  ) -> MFrame {
     let source_map = self.tcx.sess.source_map();
     let body = &frame.body;
-    let location_domain = LocationDomain::new(body);
 
     let name = match self.tcx.opt_item_name(def_id.to_def_id()) {
       Some(sym) => sym.to_ident_string(),
       None => "Unknown".to_string(),
     };
 
+    let ranges = self.frame_ranges(frame, def_id, current_loc);
+
+    let mut locals = frame
+      .locals
+      .iter_enumerated()
+      .filter_map(|(local, state)| {
+        let decl = &body.local_decls[local];
+        match state.value {
+          LocalValue::Live(_) if is_user_local(body, local) => {
+            let name = source_map.span_to_snippet(decl.source_info.span).unwrap();
+            let op_ty = match self.ecx.access_local(frame, local, state.layout.get()) {
+              Ok(op_ty) => op_ty,
+              Err(error) => {
+                self.warn_read_error(&format!("Cannot access local `{name}`"), error);
+                return None;
+              }
+            };
+            let value = self.read(&op_ty);
+            let alloc_id = local_alloc_id(state.value);
+            let bytes = match (alloc_id, op_ty.try_as_mplace()) {
+              _ if !self.show_bytes => None,
+              (Some(alloc_id), _) => self.try_read_bytes(alloc_id),
+              (None, Err(imm)) => Some(self.encode_immediate(&imm)),
+              (None, Ok(_)) => None,
+            };
+            Some(MLocal {
+              name,
+              value,
+              alloc_id: alloc_id.map(|alloc_id| alloc_id.0.get()),
+              bytes,
+            })
+          }
+          _ => None,
+        }
+      })
+      .collect::<Vec<_>>();
+    locals.sort_by_cached_key(|local| local.name.clone());
+
+    MFrame {
+      id,
+      depth,
+      name,
+      ranges,
+      locals,
+    }
+  }
+
+  fn try_read_bytes(&self, alloc_id: AllocId) -> Option<MBytes> {
+    self
+      .read_bytes(alloc_id)
+      .map_err(|error| {
+        self.warn_read_error(
+          &format!("Cannot read bytes of allocation #{}", alloc_id.0),
+          error,
+        )
+      })
+      .ok()
+  }
+
+  /// Returns the source ranges of the code that `frame` is executing.
+  fn frame_ranges(
+    &self,
+    frame: &Frame<'mir, 'tcx>,
+    def_id: LocalDefId,
+    current_loc: &Option<Result<Location, Span>>,
+  ) -> Vec<MRange> {
+    let source_map = self.tcx.sess.source_map();
+    let body = &frame.body;
+    let location_domain = LocationDomain::new(body);
+
     let spanner = self.spanner(def_id, body);
     let spans = match current_loc {
       Some(Ok(location)) => spanner.location_to_spans(
@@ -97,63 +605,493 @@ where
       None => vec![],
     };
 
-    let ranges = Span::merge_overlaps(spans)
+    Span::merge_overlaps(spans)
       .into_iter()
-      .map(|span| {
-        let range = Range::from_span(span, source_map).unwrap();
-        (range.char_start, range.char_end)
+      .filter_map(|span| MRange::from_span(span, source_map))
+      .collect()
+  }
+
+  /// Returns the source code of the traced crate.
+  pub fn source_files(&self) -> Vec<MSourceFile> {
+    let source_map = self.tcx.sess.source_map();
+    let files = source_map.files();
+    files
+      .iter()
+      .filter(|file| is_user_file(file))
+      .filter_map(|file| {
+        Some(MSourceFile {
+          path: relative_path(&file.name),
+          contents: file.src.as_ref()?.to_string(),
+        })
       })
-      .collect::<Vec<_>>();
+      .collect()
+  }
 
-    let mut locals = frame
-      .locals
-      .iter_enumerated()
-      .filter_map(|(local, state)| {
-        let decl = &body.local_decls[local];
-        match (&decl.local_info, state.value) {
-          (Some(box LocalInfo::User(ClearCrossCrate::Set(_))), LocalValue::Live(_)) => {
-            Some((|| {
-              let name = source_map.span_to_snippet(decl.source_info.span).unwrap();
-              let op_ty = self.ecx.access_local(frame, local, state.layout.get())?;
-              let value = self.read(&op_ty)?;
-              Ok((name, value))
-            })())
+  /// Returns true if the call about to be made allocates memory on behalf
+  /// of user code. Frames of the standard collections are skipped, so that
+  /// e.g. the buffer allocated by `Vec::push` belongs to its caller.
+  fn is_user_allocation(&self) -> bool {
+    match self.foreign_call() {
+      Some((name, _)) if ALLOCATORS.contains(&name.as_str()) => {}
+      _ => return false,
+    }
+    Machine::stack(&self.ecx)
+      .iter()
+      .rev()
+      .map(|frame| frame.instance.def_id())
+      .find(|def_id| {
+        let krate = self.tcx.crate_name(def_id.krate);
+        !ALLOCATING_CRATES.contains(&krate.as_str())
+          && !self
+            .tcx
+            .def_path_str(*def_id)
+            .starts_with("std::collections::")
+      })
+      .map_or(false, |def_id| def_id.is_local())
+  }
+
+  /// Reads the allocations that are known but not read yet, and any that
+  /// those point to.
+  fn read_pending(&self) {
+    loop {
+      // Allocations are read from the lowest offset anything points to.
+      // Leaked allocations are read from where they were last seen.
+      let next = self.pending.borrow().keys().next().copied();
+      let (alloc_id, offset, place) = match next {
+        Some((alloc_id, offset)) => {
+          let place = self.pending.borrow_mut().remove(&(alloc_id, offset));
+          (alloc_id, offset, place.unwrap())
+        }
+        None => {
+          let visited = self.visited.borrow();
+          let alloc_places = self.alloc_places.borrow();
+          let leaked = self
+            .user_allocs
+            .borrow()
+            .iter()
+            .filter(|alloc_id| !visited.contains(*alloc_id))
+            .find_map(|alloc_id| Some((*alloc_id, *alloc_places.get(alloc_id)?)));
+          match leaked {
+            Some((alloc_id, (offset, place))) => (alloc_id, offset, place),
+            None => break,
           }
-          _ => None,
+        }
+      };
+      if !self.visited.borrow_mut().insert(alloc_id) {
+        continue;
+      }
+      if self.heap.borrow().len() >= self.limits.max_allocations {
+        self.pending.take();
+        self.warn(format!(
+          "Only the first {} allocations are shown",
+          self.limits.max_allocations
+        ));
+        break;
+      }
+
+      // A view of the whole allocation beats an interior pointer.
+      let (offset, place) = match self.alloc_places.borrow().get(&alloc_id) {
+        Some(&(known_offset, known_place)) if known_offset <= offset => {
+          (known_offset, known_place)
+        }
+        _ => (offset, place),
+      };
+      self
+        .alloc_places
+        .borrow_mut()
+        .insert(alloc_id, (offset, place));
+      let value = self.read(&place.into());
+      self.heap.borrow_mut().insert(alloc_id, (offset, value));
+    }
+  }
+
+  fn build_heap(&self) -> MHeap {
+    let stack_allocs = Machine::stack(&self.ecx)
+      .iter()
+      .flat_map(|frame| frame.locals.iter())
+      .filter_map(|state| local_alloc_id(state.value))
+      .collect::<HashSet<_>>();
+
+    let is_live = |alloc_id: &AllocId| self.ecx.get_alloc_raw(*alloc_id).is_ok();
+    self.user_allocs.borrow_mut().retain(is_live);
+    self
+      .alloc_places
+      .borrow_mut()
+      .retain(|alloc_id, _| is_live(alloc_id));
+
+    self.read_pending();
+
+    // Leaked allocations that were never seen have no known type.
+    for alloc_id in self.user_allocs.borrow().iter() {
+      if self.heap.borrow().len() >= self.limits.max_allocations {
+        break;
+      }
+      if self.visited.borrow_mut().insert(*alloc_id) {
+        let size = self
+          .ecx
+          .get_alloc_raw(*alloc_id)
+          .map_or(0, |alloc| alloc.size().bytes());
+        let value = MValue::Opaque {
+          type_name: "unknown".to_string(),
+          size,
+        };
+        self
+          .heap
+          .borrow_mut()
+          .insert(*alloc_id, (Size::ZERO, value));
+      }
+    }
+    self.visited.take();
+
+    let allocations = self
+      .heap
+      .take()
+      .into_iter()
+      .map(|(alloc_id, (offset, value))| {
+        let size = match self.ecx.get_alloc_raw(alloc_id) {
+          Ok(alloc) => alloc.size().bytes(),
+          Err(error) => {
+            let context = format!("Cannot read size of allocation #{}", alloc_id.0);
+            self.warn_read_error(&context, error);
+            0
+          }
+        };
+        let bytes = self
+          .show_bytes
+          .then(|| self.try_read_bytes(alloc_id))
+          .flatten();
+        let kind = if self.tcx.get_global_alloc(alloc_id).is_some() {
+          MAllocationKind::Static
+        } else if stack_allocs.contains(&alloc_id) {
+          MAllocationKind::Stack
+        } else {
+          MAllocationKind::Heap
+        };
+        MAllocation {
+          id: alloc_id.0.get(),
+          kind,
+          size,
+          offset: offset.bytes(),
+          value,
+          bytes,
         }
       })
-      .collect::<InterpResult<'tcx, Vec<_>>>()?;
-    locals.sort_by_cached_key(|(k, _)| k.clone());
+      .collect();
 
-    Ok(MFrame {
-      name,
-      ranges,
-      locals,
+    MHeap { allocations }
+  }
+
+  /// Gives an id to each frame pushed since the last call, and returns
+  /// whether there were any.
+  fn sync_frame_ids(&mut self) -> bool {
+    // A step pushes or pops at most one frame, so frames keep their ids
+    // as long as they stay at the same position.
+    let stack_len = Machine::stack(&self.ecx).len();
+    let pushed = stack_len > self.frame_ids.len();
+    self.frame_ids.truncate(stack_len);
+    while self.frame_ids.len() < stack_len {
+      self.frame_ids.push(self.next_frame_id);
+      self.next_frame_id += 1;
+    }
+    pushed
+  }
+
+  fn position(&self) -> Option<Position> {
+    let stack = Machine::stack(&self.ecx);
+    let frame = stack.last()?;
+    if !frame.instance.def_id().is_local() {
+      return None;
+    }
+
+    let source_map = self.tcx.sess.source_map();
+    Some(Position {
+      frame_id: self.frame_ids[stack.len() - 1],
+      line: source_map.lookup_char_pos(frame.current_span().lo()).line,
     })
   }
 
-  pub fn step(&mut self) -> InterpResult<'tcx, Option<MFrame>> {
+  fn step_reason(&self, next: Position) -> MStepReason {
+    match self.last_position {
+      None => MStepReason::Call,
+      // Frame ids only grow, so a newer frame was called even if it is no
+      // deeper, e.g. a closure called again by `Iterator::for_each`.
+      Some(prev) if next.frame_id > prev.frame_id => MStepReason::Call,
+      Some(prev) if next.frame_id < prev.frame_id => MStepReason::Return,
+      Some(prev) if prev.line != next.line => MStepReason::Line,
+      Some(_) => MStepReason::Statement,
+    }
+  }
+
+  pub fn step(&mut self) -> InterpResult<'tcx, Option<MStep>> {
     let mut current_loc = None;
     loop {
-      let stack = Machine::stack(&self.ecx);
-      if let Some(frame) = stack.last() {
-        let def_id = frame.instance.def_id();
-        if def_id.is_local() {
-          current_loc = Some(frame.current_loc());
-        }
+      self.sync_frame_ids();
+      if let Some(position) = self.position() {
+        self.last_position = Some(position);
+        let frame = Machine::stack(&self.ecx).last().unwrap();
+        current_loc = Some(frame.current_loc());
+      }
+
+      if self.steps_taken >= self.budget.max_steps {
+        self.reach_limit(MLimit::Steps);
+        return Ok(None);
+      }
+      if self.started.elapsed() >= Duration::from_secs(self.budget.time_limit_secs) {
+        self.reach_limit(MLimit::Time);
+        return Ok(None);
       }
 
+      if let Err(error) = self.capture_output() {
+        self.warn_read_error("Cannot capture program output", error);
+      }
+      // Allocation ids are handed out in order, so the ids between two
+      // reserved ones are the allocations made in between.
+      let first_id = self
+        .is_user_allocation()
+        .then(|| self.tcx.reserve_alloc_id());
+      // Only errors from executing the program end the trace.
       if !self.ecx.step()? {
         return Ok(None);
       }
+      self.steps_taken += 1;
+      if let Some(first_id) = first_id {
+        let last_id = self.tcx.reserve_alloc_id();
+        self.user_allocs.get_mut().extend(
+          (first_id.0.get() + 1 .. last_id.0.get())
+            .filter_map(NonZeroU64::new)
+            .map(AllocId),
+        );
+      }
+
+      if self.sync_frame_ids() && self.is_panicking() {
+        self.record_panic();
+      }
+
+      let next = match self.position() {
+        Some(next) => next,
+        None => continue,
+      };
+      if !self.scope.contains(self.tcx, Machine::stack(&self.ecx)) {
+        continue;
+      }
+
+      let reason = self.step_reason(next);
+      let emit = match self.granularity {
+        Granularity::Statement => true,
+        Granularity::Line => reason != MStepReason::Statement,
+        Granularity::Call => matches!(reason, MStepReason::Call | MStepReason::Return),
+      };
+      if !emit {
+        continue;
+      }
+
+      self.read_statics();
+      let stack = self.build_stack(&current_loc);
+      let heap = self.build_heap();
+      return Ok(Some(MStep {
+        reason,
+        stack,
+        heap,
+        stdout: self.stdout.take_new(),
+        stderr: self.stderr.take_new(),
+      }));
+    }
+  }
+
+  /// Returns true if the innermost frame is the entry point shared by all
+  /// panics, after the panic payload has been created.
+  fn is_panicking(&self) -> bool {
+    let frame = Machine::stack(&self.ecx).last().unwrap();
+    self.tcx.def_path_str(frame.instance.def_id())
+      == "std::panicking::rust_panic_with_hook"
+  }
 
-      let stack = Machine::stack(&self.ecx);
-      if let Some(frame) = stack.last() {
-        let def_id = frame.instance.def_id();
-        if let Some(local_def_id) = def_id.as_local() {
-          return Ok(Some(self.build_frame(frame, local_def_id, &current_loc)?));
+  fn record_panic(&mut self) {
+    let current_loc = Machine::stack(&self.ecx)
+      .iter()
+      .rev()
+      .find(|frame| frame.instance.def_id().is_local())
+      .map(|frame| frame.current_loc());
+    let stack = self.build_stack(&current_loc);
+
+    // The values read for the panic are not part of the next step.
+    self.heap.take();
+    self.visited.take();
+    self.pending.take();
+
+    self.panic = Some(PanicState {
+      stack,
+      stderr_start: self.stderr.bytes.len(),
+    });
+  }
+
+  /// Returns the source ranges of the innermost traced frame.
+  fn innermost_ranges(&self) -> Vec<MRange> {
+    let stack = Machine::stack(&self.ecx);
+    stack
+      .iter()
+      .rev()
+      .find_map(|frame| {
+        let def_id = frame.instance.def_id().as_local()?;
+        Some(self.frame_ranges(frame, def_id, &Some(frame.current_loc())))
+      })
+      .unwrap_or_default()
+  }
+
+  fn exit_code(&self) -> InterpResult<'tcx, i64> {
+    self
+      .ecx
+      .read_scalar(&self.ret_place.into())?
+      .check_init()?
+      .to_machine_isize(&self.ecx)
+  }
+
+  fn exit(&mut self, code: i64) -> MTermination {
+    match self.panic.take() {
+      Some(panic) if code != 0 => {
+        let stderr = self.stderr.all();
+        let message = panic_message(&stderr[panic.stderr_start.min(stderr.len()) ..]);
+        let ranges = panic
+          .stack
+          .last()
+          .map(|frame| frame.ranges.clone())
+          .unwrap_or_default();
+        MTermination::Panic {
+          message,
+          ranges,
+          stack: panic.stack,
         }
       }
+      _ => MTermination::Exit { code },
     }
   }
+
+  /// Marks the trace as cut short, so that it ends with
+  /// [`MTermination::LimitReached`] rather than the program's own exit.
+  pub fn reach_limit(&mut self, limit: MLimit) {
+    self.limit_reached = Some(limit);
+  }
+
+  pub fn max_frames(&self) -> usize {
+    self.budget.max_frames
+  }
+
+  /// Describes how the program stopped, given the result of the last step.
+  pub fn terminate(&mut self, result: InterpResult<'tcx>) -> MTermination {
+    if let Some(limit) = self.limit_reached {
+      return MTermination::LimitReached { limit };
+    }
+
+    let error = match result.and_then(|()| self.exit_code()) {
+      Ok(code) => return self.exit(code),
+      Err(error) => error.into_kind(),
+    };
+
+    let message = error.to_string();
+    match error {
+      InterpError::UndefinedBehavior(_) => MTermination::UndefinedBehavior {
+        message,
+        ranges: self.innermost_ranges(),
+      },
+      InterpError::ResourceExhaustion(_) => MTermination::ResourceExhaustion { message },
+      InterpError::MachineStop(info) => match info.downcast_ref::<TerminationInfo>() {
+        Some(TerminationInfo::Exit(code)) => self.exit(*code),
+        Some(TerminationInfo::StackedBorrowsUb { .. }) => {
+          MTermination::UndefinedBehavior {
+            message,
+            ranges: self.innermost_ranges(),
+          }
+        }
+        _ => MTermination::Error { message },
+      },
+      _ => MTermination::Error { message },
+    }
+  }
+
+  /// Adds the user's statics to the heap, whether or not any local refers
+  /// to them.
+  fn read_statics(&self) {
+    for def_id in &self.statics {
+      let def_id = def_id.to_def_id();
+      let alloc_id = self.tcx.create_static_alloc(def_id);
+      if !self.visited.borrow_mut().insert(alloc_id) {
+        continue;
+      }
+
+      let value = (|| {
+        let ptr = self.ecx.global_base_pointer(Pointer::from(alloc_id))?;
+        let layout = self.ecx.layout_of(self.tcx.type_of(def_id))?;
+        let place = MPlaceTy::from_aligned_ptr(ptr.into(), layout);
+        Ok(self.read(&place.into()))
+      })();
+      match value {
+        Ok(value) => {
+          self.heap.borrow_mut().insert(alloc_id, (Size::ZERO, value));
+        }
+        Err(error) => {
+          let context = format!("Cannot read static `{}`", self.tcx.def_path_str(def_id));
+          self.warn_read_error(&context, error);
+        }
+      }
+    }
+  }
+
+  fn build_stack(&self, current_loc: &Option<Result<Location, Span>>) -> Vec<MFrame> {
+    let stack = Machine::stack(&self.ecx);
+    let frames = stack
+      .iter()
+      .zip(&self.frame_ids)
+      .filter_map(|(frame, id)| Some((frame, frame.instance.def_id().as_local()?, *id)))
+      .collect::<Vec<_>>();
+
+    // User locals are shown in their frame, so pointers to them should not
+    // copy them into the heap.
+    self
+      .visited
+      .borrow_mut()
+      .extend(frames.iter().flat_map(|(frame, _, _)| {
+        frame
+          .locals
+          .iter_enumerated()
+          .filter(|(local, _)| is_user_local(&frame.body, *local))
+          .filter_map(|(_, state)| local_alloc_id(state.value))
+      }));
+
+    let innermost = frames.len().saturating_sub(1);
+    frames
+      .into_iter()
+      .enumerate()
+      .map(|(depth, (frame, def_id, id))| {
+        // Callers are paused at the call they are waiting on.
+        let loc = if depth == innermost {
+          *current_loc
+        } else {
+          Some(frame.current_loc())
+        };
+        self.build_frame(frame, def_id, id, depth, &loc)
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn panic_message_test() {
+    let stderr = "thread 'main' panicked at 'oh no', src/main.rs:2:5\n\
+                  note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+    assert_eq!(panic_message(stderr), "oh no");
+
+    let stderr = "thread 'main' panicked at 'expected 'a', found 'b'', src/main.rs:2:5\n\
+                  note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+    assert_eq!(panic_message(stderr), "expected 'a', found 'b'");
+
+    let stderr = "thread 'main' panicked at 'oh no', src/main.rs:2:5\n";
+    assert_eq!(panic_message(stderr), "oh no");
+
+    let stderr = "fatal runtime error: stack overflow\n";
+    assert_eq!(panic_message(stderr), "fatal runtime error: stack overflow");
+  }
 }
@@ -2,6 +2,7 @@
 
 extern crate either;
 extern crate rustc_apfloat;
+extern crate rustc_ast;
 extern crate rustc_data_structures;
 extern crate rustc_driver;
 extern crate rustc_hir;
@@ -13,52 +14,121 @@ extern crate rustc_span;
 extern crate rustc_target;
 extern crate rustc_type_ir;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, io, process};
 
+use anyhow::Context;
 use rustc_driver::Compilation;
 use rustc_hir::def_id::{DefId, CRATE_DEF_ID};
 use rustc_interface::interface;
 use rustc_resolve::{Namespace, ParentScope};
-use rustc_session::CtfeBacktrace;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 mod eval;
 mod mvalue;
+mod renderer;
+mod scope;
 
-pub use eval::MFrame;
-pub use mvalue::MValue;
+pub use eval::{
+  Budget, Granularity, MFrame, MLimit, MLocal, MRange, MSourceFile, MStep, MStepReason,
+  MTermination,
+};
+pub use mvalue::{
+  MAllocation, MAllocationKind, MBorrowState, MByte, MBytes, MField, MFloat, MHeap,
+  MLockKind, MPointerKind, MRcKind, MValue, ReadLimits,
+};
+use renderer::BUILTINS;
+pub use renderer::{CustomView, RendererConfig, ValueRenderer};
 
 #[derive(Serialize, Deserialize, TS)]
 #[ts(export)]
-pub struct MOutput(pub Vec<MFrame>);
+pub struct MOutput {
+  pub steps: Vec<MStep>,
+  pub termination: MTermination,
+  pub warnings: Vec<String>,
+  /// Everything the program wrote, including output after the last step.
+  pub stdout: String,
+  pub stderr: String,
+  /// Every file that ranges in the trace may refer to.
+  pub sources: Vec<MSourceFile>,
+}
+
+pub struct TypeDefIds {
+  renderers: HashMap<DefId, Box<dyn ValueRenderer>>,
+  /// Custom renderers whose path could not be resolved, shown to the author
+  /// as warnings.
+  pub(crate) unresolved: Vec<String>,
+}
 
-const TYPES: &[&str] = &["std::vec::Vec"];
-pub struct TypeDefIds(HashMap<DefId, String>);
 impl TypeDefIds {
-  pub fn new(queries: &rustc_interface::Queries) -> Self {
+  pub fn new(queries: &rustc_interface::Queries, custom: &[RendererConfig]) -> Self {
+    let renderers = BUILTINS
+      .iter()
+      .map(|(path, builtin)| (*path, false, Box::new(*builtin) as Box<dyn ValueRenderer>))
+      .chain(custom.iter().map(|config| {
+        (
+          config.path.as_str(),
+          true,
+          Box::new(config.view.clone()) as Box<dyn ValueRenderer>,
+        )
+      }));
+
     let (_, resolver, _) = &*queries.expansion().unwrap().peek();
     let mut map = HashMap::new();
+    let mut unresolved = Vec::new();
     resolver.borrow_mut().access(|resolver| {
       let parent_scope =
         ParentScope::module(resolver.expect_module(CRATE_DEF_ID.to_def_id()), resolver);
-      for path in TYPES {
-        let resolved = resolver
-          .resolve_rustdoc_path(path, Namespace::TypeNS, parent_scope)
-          .unwrap();
-        map.insert(resolved.def_id(), path.to_string());
+      for (path, is_custom, renderer) in renderers {
+        match resolver.resolve_rustdoc_path(path, Namespace::TypeNS, parent_scope) {
+          Some(resolved) => {
+            map.insert(resolved.def_id(), renderer);
+          }
+          None if is_custom => {
+            unresolved.push(format!("Could not resolve path for renderer: `{path}`"))
+          }
+          None => log::warn!("Could not resolve path for renderer: `{path}`"),
+        }
       }
     });
-    TypeDefIds(map)
+    TypeDefIds {
+      renderers: map,
+      unresolved,
+    }
   }
 
-  pub fn get_path(&self, def_id: DefId) -> Option<&String> {
-    self.0.get(&def_id)
+  pub fn get_renderer(&self, def_id: DefId) -> Option<&dyn ValueRenderer> {
+    self.renderers.get(&def_id).map(|renderer| &**renderer)
+  }
+}
+
+const CONFIG_FILE: &str = "mirivis.json";
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct MirivisConfig {
+  pub renderers: Vec<RendererConfig>,
+  pub limits: ReadLimits,
+  /// Include the raw bytes of each allocation alongside its value.
+  pub show_bytes: bool,
+  pub budget: Budget,
+  pub granularity: Granularity,
+}
+
+impl MirivisConfig {
+  /// Loads the config from `mirivis.json` in the current directory, if it exists.
+  pub fn load() -> anyhow::Result<Self> {
+    match fs::read_to_string(CONFIG_FILE) {
+      Ok(contents) => serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {CONFIG_FILE}")),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(MirivisConfig::default()),
+      Err(e) => Err(e).with_context(|| format!("Failed to read {CONFIG_FILE}")),
+    }
   }
 }
 
-#[derive(Default)]
 struct Callbacks {
+  config: MirivisConfig,
   type_def_ids: Option<TypeDefIds>,
 }
 
@@ -70,7 +140,7 @@ impl rustc_driver::Callbacks for Callbacks {
   ) -> Compilation {
     compiler.session().abort_if_errors();
 
-    self.type_def_ids = Some(TypeDefIds::new(queries));
+    self.type_def_ids = Some(TypeDefIds::new(queries, &self.config.renderers));
 
     Compilation::Continue
   }
@@ -82,35 +152,55 @@ impl rustc_driver::Callbacks for Callbacks {
   ) -> Compilation {
     queries.global_ctxt().unwrap().peek_mut().enter(|tcx| {
       let mut evaluator =
-        eval::VisEvaluator::new(tcx, self.type_def_ids.take().unwrap()).unwrap();
-      *tcx.sess.ctfe_backtrace.borrow_mut() = CtfeBacktrace::Capture;
+        eval::VisEvaluator::new(tcx, self.type_def_ids.take().unwrap(), &self.config)
+          .unwrap();
 
-      let mut frames: Vec<MFrame> = Vec::new();
-      loop {
+      let mut steps: Vec<MStep> = Vec::new();
+      let result = loop {
         match evaluator.step() {
-          Ok(Some(frame)) => {
-            let is_different = match frames.last() {
-              Some(last) => last.locals != frame.locals,
+          Ok(Some(step)) => {
+            // Statement-level steps are only kept if something visibly changed.
+            let is_different = match steps.last() {
+              Some(_) if step.reason != MStepReason::Statement => true,
+              Some(last) => {
+                let locals = |step: &MStep| {
+                  step
+                    .stack
+                    .iter()
+                    .map(|frame| &frame.locals)
+                    .collect::<Vec<_>>()
+                };
+                locals(last) != locals(&step)
+                  || last.heap != step.heap
+                  || !step.stdout.is_empty()
+                  || !step.stderr.is_empty()
+              }
               None => true,
             };
             if is_different {
-              frames.push(frame);
+              steps.push(step);
+              if steps.len() >= evaluator.max_frames() {
+                evaluator.reach_limit(MLimit::Frames);
+                break Ok(());
+              }
             }
           }
-          Ok(None) => {
-            break;
-          }
-          Err(e) => {
-            e.print_backtrace();
-            panic!("{}", e.into_kind())
-          }
+          Ok(None) => break Ok(()),
+          Err(e) => break Err(e),
         }
-      }
+      };
+      let termination = evaluator.terminate(result);
 
-      println!(
-        "{}",
-        serde_json::to_string_pretty(&MOutput(frames)).unwrap()
-      );
+      let (stdout, stderr) = evaluator.output();
+      let output = MOutput {
+        steps,
+        termination,
+        warnings: evaluator.take_warnings(),
+        stdout,
+        stderr,
+        sources: evaluator.source_files(),
+      };
+      println!("{}", serde_json::to_string_pretty(&output).unwrap());
     });
 
     compiler.session().abort_if_errors();
@@ -119,10 +209,25 @@ impl rustc_driver::Callbacks for Callbacks {
   }
 }
 
+/// Returns true if the crate root enables `feature` itself, e.g. with
+/// `#![feature(register_tool)]`.
+fn declares_feature(compiler_args: &[String], feature: &str) -> bool {
+  compiler_args
+    .iter()
+    .filter(|arg| arg.ends_with(".rs"))
+    .filter_map(|path| fs::read_to_string(path).ok())
+    .any(|src| {
+      src.split("#![feature(").skip(1).any(|attr| {
+        let features = attr.split(")]").next().unwrap_or_default();
+        features.split(',').any(|name| name.trim() == feature)
+      })
+    })
+}
+
 pub struct MirivisPlugin;
 
 impl rustc_plugin::RustcPlugin for MirivisPlugin {
-  type Args = ();
+  type Args = MirivisConfig;
 
   fn bin_name() -> String {
     "mirivis-driver".to_owned()
@@ -132,8 +237,12 @@ impl rustc_plugin::RustcPlugin for MirivisPlugin {
     &self,
     _target_dir: &rustc_plugin::Utf8Path,
   ) -> rustc_plugin::RustcPluginArgs<Self::Args> {
+    let config = MirivisConfig::load().unwrap_or_else(|e| {
+      eprintln!("error: {e:#}");
+      process::exit(1);
+    });
     rustc_plugin::RustcPluginArgs {
-      args: (),
+      args: config,
       flags: None,
       file: None,
     }
@@ -141,9 +250,20 @@ impl rustc_plugin::RustcPlugin for MirivisPlugin {
 
   fn run(
     self,
-    compiler_args: Vec<String>,
-    _plugin_args: Self::Args,
+    mut compiler_args: Vec<String>,
+    plugin_args: Self::Args,
   ) -> rustc_interface::interface::Result<()> {
-    rustc_driver::RunCompiler::new(&compiler_args, &mut Callbacks::default()).run()
+    // Lets programs mark functions to trace with `#[situ::trace]`. Enabling
+    // a feature twice is an error, so crates that enable it are left alone.
+    if !declares_feature(&compiler_args, "register_tool") {
+      compiler_args.push("-Zcrate-attr=feature(register_tool)".to_owned());
+    }
+    compiler_args.push("-Zcrate-attr=register_tool(situ)".to_owned());
+
+    let mut callbacks = Callbacks {
+      config: plugin_args,
+      type_def_ids: None,
+    };
+    rustc_driver::RunCompiler::new(&compiler_args, &mut callbacks).run()
   }
 }
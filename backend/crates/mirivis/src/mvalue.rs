@@ -1,14 +1,19 @@
 use miri::{
-  Immediate, InterpCx, InterpResult, Machine, MemPlaceMeta, OpTy, Provenance, Value,
+  AllocId, FnVal, ImmTy, Immediate, InterpCx, InterpErrorInfo, InterpResult, MPlaceTy,
+  Machine, MemPlaceMeta, OpTy, Provenance, Scalar, ScalarMaybeUninit, Value,
 };
 use rustc_apfloat::Float;
-use rustc_middle::ty::{AdtKind, FieldDef, TyKind};
-use rustc_target::abi::Size;
+use rustc_hir::{def_id::DefId, Mutability};
+use rustc_middle::ty::{
+  layout::{LayoutOf, TyAndLayout},
+  AdtKind, FieldDef, Ty, TyKind,
+};
+use rustc_target::abi::{Abi, Endian, Size};
 use rustc_type_ir::FloatTy;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use crate::eval::VisEvaluator;
+use crate::eval::{MRange, VisEvaluator};
 
 #[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
 #[serde(tag = "type", content = "value")]
@@ -16,19 +21,274 @@ use crate::eval::VisEvaluator;
 pub enum MValue {
   Bool(bool),
   Char(String),
-  Uint(u64),
-  Int(i64),
-  Float(f64),
+  Uint {
+    ty: String,
+    value: String,
+  },
+  Int {
+    ty: String,
+    value: String,
+  },
+  Float {
+    ty: String,
+    value: MFloat,
+  },
   Struct {
     name: String,
-    fields: Vec<(String, MValue)>,
+    /// The fully printed type, including generic arguments.
+    ty: String,
+    size: u64,
+    align: u64,
+    fields: Vec<MField>,
+  },
+  Enum {
+    name: String,
+    variant: String,
+    fields: Vec<MField>,
+  },
+  Tuple(Vec<MValue>),
+  Array {
+    elem_ty: String,
+    len: u64,
+    elements: Vec<MValue>,
+  },
+  String {
+    value: String,
+    len: u64,
+    capacity: Option<u64>,
+    /// The allocation holding the bytes of an owned `String`.
+    buffer: Option<u64>,
+    valid_utf8: bool,
+    /// Number of bytes past the end of `value` that were not read.
+    remaining: u64,
+  },
+  /// A `Vec`, whose elements are shown in the heap entry of `buffer`.
+  Vec {
+    len: u64,
+    /// Slots past `len` are allocated but uninitialized.
+    capacity: u64,
+    /// The allocation holding the elements, if any has been made.
+    buffer: Option<u64>,
+    /// Whether the buffer has moved since this `Vec` was last read.
+    reallocated: bool,
+  },
+  Pointer {
+    alloc_id: u64,
+    offset: u64,
+    kind: MPointerKind,
+  },
+  Rc {
+    kind: MRcKind,
+    pointer: Box<MValue>,
+    strong: u64,
+    weak: u64,
+  },
+  Map {
+    entries: Vec<(MValue, MValue)>,
+  },
+  Set {
+    elements: Vec<MValue>,
+  },
+  Deque {
+    elements: Vec<MValue>,
+    head: u64,
+    capacity: u64,
+  },
+  Grid {
+    name: String,
+    rows: Vec<Vec<MValue>>,
+  },
+  Cell(Box<MValue>),
+  RefCell {
+    value: Box<MValue>,
+    state: MBorrowState,
+  },
+  Lock {
+    kind: MLockKind,
+    value: Box<MValue>,
+    state: MBorrowState,
+    poisoned: bool,
+  },
+  Closure {
+    /// Where the closure is defined, if in the traced crate.
+    range: Option<MRange>,
+    upvars: Vec<(String, MValue)>,
+  },
+  FnPtr {
+    name: String,
+  },
+  Dyn {
+    dyn_ty: String,
+    ty: String,
+    value: Box<MValue>,
+  },
+  /// Elements of a collection past [`ReadLimits::max_elements`].
+  Truncated {
+    remaining: u64,
+  },
+  /// A value nested deeper than [`ReadLimits::max_depth`].
+  TooDeep,
+  Opaque {
+    type_name: String,
+    size: u64,
   },
-  String(String),
-  Vec(Vec<MValue>),
+  /// A value whose bytes have not been initialized, e.g. a local that is
+  /// declared before it is assigned.
+  Uninit,
   Unallocated,
 }
 
-trait OpTyExt<'mir, 'tcx, Tag: Provenance, M: Machine<'mir, 'tcx>>: Sized {
+/// Bounds on how much of a value is read, so that large or deeply nested
+/// values do not blow up the size of the trace.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReadLimits {
+  /// Maximum number of elements read from any collection.
+  pub max_elements: usize,
+  /// Maximum nesting depth of a value within one allocation.
+  pub max_depth: usize,
+  /// Maximum number of allocations shown in the heap of each step.
+  pub max_allocations: usize,
+  /// Maximum number of bytes read from any string.
+  pub max_string_len: usize,
+  /// Maximum number of raw bytes shown for any local or allocation.
+  pub max_bytes: usize,
+}
+
+impl Default for ReadLimits {
+  fn default() -> Self {
+    ReadLimits {
+      max_elements: 100,
+      max_depth: 32,
+      max_allocations: 100,
+      max_string_len: 1000,
+      max_bytes: 256,
+    }
+  }
+}
+
+/// Floats with the non-finite values tagged explicitly, since JSON has no
+/// representation for them.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[serde(tag = "type", content = "value")]
+#[ts(export)]
+pub enum MFloat {
+  Finite(f64),
+  NaN,
+  Infinity,
+  NegInfinity,
+}
+
+impl From<f64> for MFloat {
+  fn from(f: f64) -> Self {
+    if f.is_nan() {
+      MFloat::NaN
+    } else if f == f64::INFINITY {
+      MFloat::Infinity
+    } else if f == f64::NEG_INFINITY {
+      MFloat::NegInfinity
+    } else {
+      MFloat::Finite(f)
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MPointerKind {
+  Ref,
+  RefMut,
+  Raw,
+  Box,
+  Shared,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MRcKind {
+  Rc,
+  RcWeak,
+  Arc,
+  ArcWeak,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[serde(tag = "type", content = "value")]
+#[ts(export)]
+pub enum MBorrowState {
+  Unborrowed,
+  Shared(u64),
+  Exclusive,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MLockKind {
+  Mutex,
+  RwLock,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq, Clone, Copy)]
+#[ts(export)]
+pub enum MAllocationKind {
+  Stack,
+  Heap,
+  Static,
+}
+
+/// A single byte of an allocation, as stored by the interpreter.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MByte {
+  /// The value of the byte, or `None` if it is uninitialized.
+  pub value: Option<u8>,
+  /// The allocation pointed to, if this byte is part of a stored pointer.
+  pub provenance: Option<u64>,
+}
+
+/// The leading bytes of a local or allocation, up to
+/// [`ReadLimits::max_bytes`].
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MBytes {
+  pub bytes: Vec<MByte>,
+  /// Number of bytes past the end of `bytes` that were not read.
+  pub remaining: u64,
+}
+
+/// A field in declaration order, with its byte offset into the enclosing
+/// value. Offsets may not be monotonic since rustc is free to reorder fields.
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MField {
+  pub name: String,
+  pub offset: u64,
+  pub value: MValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MAllocation {
+  pub id: u64,
+  pub kind: MAllocationKind,
+  /// Size of the whole allocation in bytes.
+  pub size: u64,
+  /// Offset of `value` in the allocation, which is only non-zero when
+  /// nothing pointed to the whole allocation.
+  pub offset: u64,
+  pub value: MValue,
+  pub bytes: Option<MBytes>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, PartialEq)]
+#[ts(export)]
+pub struct MHeap {
+  pub allocations: Vec<MAllocation>,
+}
+
+pub(crate) trait OpTyExt<'mir, 'tcx, Tag: Provenance, M: Machine<'mir, 'tcx>>:
+  Sized
+{
   fn field_by_name(
     &self,
     name: &str,
@@ -69,107 +329,1080 @@ impl<'hir, 'mir, 'tcx> VisEvaluator<'hir, 'mir, 'tcx>
 where
   'tcx: 'hir,
 {
-  fn read_raw_vec(
+  pub(super) fn read_usize(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, u64> {
+    self
+      .ecx
+      .read_scalar(op)?
+      .check_init()?
+      .to_machine_usize(&self.ecx)
+  }
+
+  /// Returns the buffer of a `RawVec`, or `None` if it has not been allocated.
+  fn deref_raw_vec(
     &self,
     op: &OpTy<'tcx, miri::Tag>,
-    len: u64,
-  ) -> InterpResult<'tcx, Vec<MValue>> {
+  ) -> InterpResult<'tcx, Option<MPlaceTy<'tcx, miri::Tag>>> {
     let (_, unique_t) = op.field_by_name("ptr", &self.ecx)?;
     let (_, nonzero_t) = unique_t.field_by_name("pointer", &self.ecx)?;
     let (_, ptr) = nonzero_t.field_by_name("pointer", &self.ecx)?;
-    let place = match self.ecx.deref_operand(&ptr) {
-      Ok(op) => op,
-      Err(_) => {
-        return Ok(vec![MValue::Unallocated]);
+    Ok(self.ecx.deref_operand(&ptr).ok())
+  }
+
+  /// Fallback for values that mirivis does not know how to read.
+  pub(super) fn opaque(&self, op: &OpTy<'tcx, miri::Tag>) -> MValue {
+    let type_name = op.layout.ty.to_string();
+    self.warn(format!("Cannot visualize values of type `{type_name}`"));
+    MValue::Opaque {
+      type_name,
+      size: op.layout.size.bytes(),
+    }
+  }
+
+  /// Fallback for values that mirivis failed to read.
+  fn unreadable(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    error: InterpErrorInfo<'tcx>,
+  ) -> MValue {
+    let type_name = op.layout.ty.to_string();
+    self.warn_read_error(&format!("Cannot read value of type `{type_name}`"), error);
+    MValue::Opaque {
+      type_name,
+      size: op.layout.size.bytes(),
+    }
+  }
+
+  fn read_closure(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    def_id: DefId,
+  ) -> InterpResult<'tcx, MValue> {
+    let range = def_id.as_local().and_then(|_| {
+      MRange::from_span(self.tcx.def_span(def_id), self.tcx.sess.source_map())
+    });
+
+    // The closure's fields are its upvars, in the same order as its captures.
+    let upvars = self
+      .tcx
+      .closure_min_captures_flattened(def_id)
+      .enumerate()
+      .map(|(i, place)| {
+        let upvar_op = op.project_field(&self.ecx, i)?;
+        Ok((place.to_string(self.tcx), self.read(&upvar_op)))
+      })
+      .collect::<InterpResult<'tcx, Vec<_>>>()?;
+
+    Ok(MValue::Closure { range, upvars })
+  }
+
+  fn read_fn_ptr(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, MValue> {
+    let ptr = self.ecx.read_pointer(op)?;
+    let name = match self.ecx.get_ptr_fn(ptr)? {
+      FnVal::Instance(instance) => self.tcx.def_path_str(instance.def_id()),
+      FnVal::Other(dlsym) => format!("{dlsym:?}"),
+    };
+    Ok(MValue::FnPtr { name })
+  }
+
+  /// Reads a `dyn Trait` place as its concrete type, found through its vtable.
+  fn read_dyn(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, MValue> {
+    let place = op.try_as_mplace().unwrap();
+    let vtable = match place.meta {
+      MemPlaceMeta::Meta(vtable) => vtable.to_pointer(&self.ecx)?,
+      MemPlaceMeta::None | MemPlaceMeta::Poison => unreachable!(),
+    };
+    let (_, concrete_ty) = self.ecx.read_drop_type_from_vtable(vtable)?;
+    let concrete_layout = self.ecx.layout_of(concrete_ty)?;
+    let concrete_place = MPlaceTy::from_aligned_ptr(place.ptr, concrete_layout);
+    Ok(MValue::Dyn {
+      dyn_ty: op.layout.ty.to_string(),
+      ty: concrete_ty.to_string(),
+      value: Box::new(self.read(&concrete_place.into())),
+    })
+  }
+
+  /// Reads the length, capacity and first element of a `Vec`.
+  fn vec_parts(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, (u64, u64, Option<MPlaceTy<'tcx, miri::Tag>>)> {
+    let (_, len_field) = op.field_by_name("len", &self.ecx)?;
+    let len = self.read_usize(&len_field)?;
+    let (_, buf_field) = op.field_by_name("buf", &self.ecx)?;
+    let (_, cap_field) = buf_field.field_by_name("cap", &self.ecx)?;
+    let place = self.deref_raw_vec(&buf_field)?;
+    // Like `RawVec::capacity`, zero-sized elements never need a buffer.
+    let capacity = match &place {
+      Some(place) if place.layout.is_zst() => {
+        self.tcx.data_layout.pointer_size.unsigned_int_max() as u64
       }
+      _ => self.read_usize(&cap_field)?,
     };
+    Ok((len, capacity, place))
+  }
+
+  /// Reads the elements of a `Vec` without adding its buffer to the heap.
+  pub(super) fn read_vec_elements(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, Vec<MValue>> {
+    let (len, _, place) = self.vec_parts(op)?;
+    match place {
+      Some(place) => self.read_elements(&place, place.layout, len),
+      None => Ok(Vec::new()),
+    }
+  }
+
+  pub(super) fn read_vec(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (len, capacity, place) = self.vec_parts(op)?;
+    let buffer = place.as_ref().and_then(|place| self.place_alloc(place));
+
+    // Only the `Vec` knows which slots of its buffer are initialized, so it
+    // reads the buffer for the heap rather than a pointer into it.
+    if let (Some(place), Some((alloc_id, _))) = (&place, buffer) {
+      if self.visited.borrow_mut().insert(alloc_id) {
+        let (shown, truncated) = self.limit(capacity);
+        let initialized = len.min(shown);
+        let mut elements = self.read_elements(place, place.layout, initialized)?;
+        elements.extend((initialized .. shown).map(|_| MValue::Uninit));
+        elements.extend(truncated);
+        let value = MValue::Array {
+          elem_ty: place.layout.ty.to_string(),
+          len: capacity,
+          elements,
+        };
+        self.heap.borrow_mut().insert(alloc_id, (Size::ZERO, value));
+      }
+    }
+
+    // A `Vec` is identified across steps by its own location, so moving the
+    // `Vec` itself is not mistaken for a reallocation.
+    let reallocated = match op.try_as_mplace() {
+      Ok(vec_place) => match (self.place_alloc(&vec_place), buffer) {
+        (Some(location), Some((alloc_id, _))) => {
+          let prev = self.vec_buffers.borrow_mut().insert(location, alloc_id);
+          matches!(prev, Some(prev) if prev != alloc_id)
+        }
+        _ => false,
+      },
+      Err(_) => false,
+    };
+
+    Ok(MValue::Vec {
+      len,
+      capacity,
+      buffer: buffer.map(|(alloc_id, _)| alloc_id.0.get()),
+      reallocated,
+    })
+  }
 
+  pub(super) fn read_string(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, vec_field) = op.field_by_name("vec", &self.ecx)?;
+    let (len, capacity, place) = self.vec_parts(&vec_field)?;
+    let (value, valid_utf8, remaining) = match &place {
+      Some(place) => self.read_utf8(place, len)?,
+      None => (String::new(), true, 0),
+    };
+
+    // The text is also shown inline, since that is how strings are read.
+    let buffer = place.as_ref().and_then(|place| self.place_alloc(place));
+    if let Some((alloc_id, _)) = buffer {
+      if self.visited.borrow_mut().insert(alloc_id) {
+        let contents = MValue::String {
+          value: value.clone(),
+          len,
+          capacity: None,
+          buffer: None,
+          valid_utf8,
+          remaining,
+        };
+        self
+          .heap
+          .borrow_mut()
+          .insert(alloc_id, (Size::ZERO, contents));
+      }
+    }
+
+    Ok(MValue::String {
+      value,
+      len,
+      capacity: Some(capacity),
+      buffer: buffer.map(|(alloc_id, _)| alloc_id.0.get()),
+      valid_utf8,
+      remaining,
+    })
+  }
+
+  /// Reads up to `len` bytes starting at `place`, decoding them as UTF-8 if
+  /// possible. Also returns whether they were valid UTF-8, and how many of
+  /// the `len` bytes were left unread.
+  fn read_utf8(
+    &self,
+    place: &MPlaceTy<'tcx, miri::Tag>,
+    len: u64,
+  ) -> InterpResult<'tcx, (String, bool, u64)> {
+    let max_len = self.limits.max_string_len as u64;
+    let truncated = len > max_len;
+    let read_len = len.min(max_len);
+    let bytes = self.read_u8s(place, read_len)?;
+    Ok(match String::from_utf8(bytes) {
+      Ok(s) => (s, true, len - read_len),
+      // Truncation can split the last character, which is not an error.
+      Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+        let valid_up_to = e.utf8_error().valid_up_to();
+        let mut bytes = e.into_bytes();
+        bytes.truncate(valid_up_to);
+        let s = String::from_utf8(bytes).unwrap();
+        (s, true, len - valid_up_to as u64)
+      }
+      Err(e) => (
+        String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        false,
+        len - read_len,
+      ),
+    })
+  }
+
+  /// Reads `len` bytes starting at `place`.
+  pub(super) fn read_u8s(
+    &self,
+    place: &MPlaceTy<'tcx, miri::Tag>,
+    len: u64,
+  ) -> InterpResult<'tcx, Vec<u8>> {
+    let u8_layout = self.ecx.layout_of(self.tcx.types.u8)?;
     (0 .. len)
       .map(|i| {
-        let offset = place.layout.size * i;
+        let byte_place = place.offset(
+          Size::from_bytes(i),
+          MemPlaceMeta::None,
+          u8_layout,
+          &self.ecx,
+        )?;
+        self
+          .ecx
+          .read_scalar(&byte_place.into())?
+          .check_init()?
+          .to_u8()
+      })
+      .collect()
+  }
+
+  /// Splits a collection of `len` elements into the number of elements to
+  /// read, and a marker for the elements that are left out.
+  fn limit(&self, len: u64) -> (u64, Option<MValue>) {
+    let max_elements = self.limits.max_elements as u64;
+    if len > max_elements {
+      let remaining = len - max_elements;
+      (max_elements, Some(MValue::Truncated { remaining }))
+    } else {
+      (len, None)
+    }
+  }
+
+  fn read_elements(
+    &self,
+    place: &MPlaceTy<'tcx, miri::Tag>,
+    elem_layout: TyAndLayout<'tcx>,
+    len: u64,
+  ) -> InterpResult<'tcx, Vec<MValue>> {
+    let (len, truncated) = self.limit(len);
+    let mut elements = (0 .. len)
+      .map(|i| {
+        let offset = elem_layout.size * i;
         let offset_place =
-          place.offset(offset, MemPlaceMeta::None, place.layout, &self.ecx)?;
-        self.read(&offset_place.into())
+          place.offset(offset, MemPlaceMeta::None, elem_layout, &self.ecx)?;
+        Ok(self.read(&offset_place.into()))
       })
-      .collect::<InterpResult<'tcx, Vec<_>>>()
+      .collect::<InterpResult<'tcx, Vec<_>>>()?;
+    elements.extend(truncated);
+    Ok(elements)
+  }
+
+  /// Reads a pointer as a reference into the heap section, recording the
+  /// contents of the allocation it points to if it has not been visited yet.
+  /// Pointers to visited allocations are emitted as just their id, which
+  /// makes aliasing explicit and makes cyclic structures terminate.
+  pub(super) fn read_pointer(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    kind: MPointerKind,
+  ) -> InterpResult<'tcx, MValue> {
+    match self.ecx.deref_operand(op) {
+      Ok(place) => self.pointer_to(place, kind),
+      Err(_) => Ok(MValue::Unallocated),
+    }
   }
 
-  pub(super) fn read(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, MValue> {
+  /// Returns the allocation and offset that `place` points into.
+  fn place_alloc(&self, place: &MPlaceTy<'tcx, miri::Tag>) -> Option<(AllocId, Size)> {
+    let (tag, offset) = place.ptr.into_parts();
+    Some((tag?.get_alloc_id()?, offset))
+  }
+
+  fn pointer_to(
+    &self,
+    place: MPlaceTy<'tcx, miri::Tag>,
+    kind: MPointerKind,
+  ) -> InterpResult<'tcx, MValue> {
+    // Zero-sized values like closures without captures are not allocated,
+    // so a `Box<dyn Fn()>` of one is a dangling pointer with a vtable.
+    let size = self
+      .ecx
+      .size_and_align_of_mplace(&place)?
+      .map(|(size, _)| size);
+    if size == Some(Size::ZERO) {
+      return Ok(self.read(&place.into()));
+    }
+
+    let (alloc_id, offset) = match self.place_alloc(&place) {
+      Some(location) => location,
+      None => return Ok(MValue::Unallocated),
+    };
+
+    // Pointees are read later as heap entries of their own, so a long chain
+    // of pointers does not count against the depth limit. Only a pointer to
+    // the whole allocation knows the allocation's type, so interior pointers
+    // are used only if no such pointer is found.
+    if !self.visited.borrow().contains(&alloc_id) {
+      let alloc_size = self.ecx.get_alloc_raw(alloc_id)?.size();
+      if offset == Size::ZERO && size == Some(alloc_size) {
+        self
+          .alloc_places
+          .borrow_mut()
+          .insert(alloc_id, (offset, place));
+      }
+      self
+        .pending
+        .borrow_mut()
+        .entry((alloc_id, offset))
+        .or_insert(place);
+    }
+
+    Ok(MValue::Pointer {
+      alloc_id: alloc_id.0.get(),
+      offset: offset.bytes(),
+      kind,
+    })
+  }
+
+  /// Reads the raw contents of an allocation, including which bytes are
+  /// initialized and which bytes hold pointers.
+  pub(super) fn read_bytes(&self, alloc_id: AllocId) -> InterpResult<'tcx, MBytes> {
+    let alloc = self.ecx.get_alloc_raw(alloc_id)?;
+    let total = alloc.size().bytes_usize();
+    let size = total.min(self.limits.max_bytes);
+    let bytes = alloc.inspect_with_uninit_and_ptr_outside_interpreter(0 .. size);
+
+    let ptr_size = self.tcx.data_layout.pointer_size.bytes_usize();
+    let mut provenance = vec![None; size];
+    for &(offset, tag) in alloc.relocations().iter() {
+      let start = offset.bytes_usize().min(size);
+      let alloc_id = tag.get_alloc_id().map(|alloc_id| alloc_id.0.get());
+      for byte in &mut provenance[start .. (start + ptr_size).min(size)] {
+        *byte = alloc_id;
+      }
+    }
+
+    let bytes = bytes
+      .iter()
+      .zip(provenance)
+      .enumerate()
+      .map(|(i, (byte, provenance))| {
+        let (start, end) = (Size::from_bytes(i), Size::from_bytes(i + 1));
+        let init = alloc.init_mask().is_range_initialized(start, end).is_ok();
+        MByte {
+          value: init.then(|| *byte),
+          provenance,
+        }
+      })
+      .collect();
+    Ok(MBytes {
+      bytes,
+      remaining: (total - size) as u64,
+    })
+  }
+
+  /// Lays out an immediate the way it would be stored in memory, since the
+  /// interpreter keeps locals whose address is never taken out of memory.
+  pub(super) fn encode_immediate(&self, imm: &ImmTy<'tcx, miri::Tag>) -> MBytes {
+    let data_layout = &self.tcx.data_layout;
+    let total = imm.layout.size.bytes_usize();
+    let mut bytes = (0 .. total)
+      .map(|_| MByte {
+        value: None,
+        provenance: None,
+      })
+      .collect::<Vec<_>>();
+
+    let scalars = match (**imm, imm.layout.abi) {
+      (Immediate::Scalar(a), _) => vec![(Size::ZERO, a)],
+      (Immediate::ScalarPair(a, b), Abi::ScalarPair(a_abi, b_abi)) => {
+        let b_offset = a_abi
+          .size(data_layout)
+          .align_to(b_abi.align(data_layout).abi);
+        vec![(Size::ZERO, a), (b_offset, b)]
+      }
+      _ => Vec::new(),
+    };
+    for (offset, scalar) in scalars {
+      // Pointers are stored as their address, like in an allocation.
+      let (bits, size, provenance) = match scalar {
+        ScalarMaybeUninit::Scalar(Scalar::Int(int)) => {
+          (int.assert_bits(int.size()), int.size(), None)
+        }
+        ScalarMaybeUninit::Scalar(Scalar::Ptr(ptr, size)) => {
+          let (tag, addr) = ptr.into_parts();
+          let provenance = tag.get_alloc_id().map(|alloc_id| alloc_id.0.get());
+          (addr.bytes() as u128, Size::from_bytes(size), provenance)
+        }
+        ScalarMaybeUninit::Uninit => continue,
+      };
+      let size = size.bytes_usize();
+      let encoded = match data_layout.endian {
+        Endian::Little => bits.to_le_bytes()[.. size].to_vec(),
+        Endian::Big => bits.to_be_bytes()[16 - size ..].to_vec(),
+      };
+      let start = offset.bytes_usize();
+      for (byte, value) in bytes[start .. start + size].iter_mut().zip(encoded) {
+        *byte = MByte {
+          value: Some(value),
+          provenance,
+        };
+      }
+    }
+
+    let size = total.min(self.limits.max_bytes);
+    bytes.truncate(size);
+    MBytes {
+      bytes,
+      remaining: (total - size) as u64,
+    }
+  }
+
+  /// Projects through any number of single-field wrappers down to the
+  /// integer inside, e.g. a `Cell<usize>` or an `AtomicU32`.
+  fn unwrap_integer(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, OpTy<'tcx, miri::Tag>> {
+    let mut op = *op;
+    while let TyKind::Adt(..) = op.layout.ty.kind() {
+      op = op.project_field(&self.ecx, 0)?;
+    }
+    Ok(op)
+  }
+
+  /// Reads an integer of any width as an index, or `None` if `op` is not an
+  /// integer or does not fit in a `u64`.
+  pub(super) fn read_index(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, Option<u64>> {
+    let op = self.unwrap_integer(op)?;
+    let size = op.layout.size;
+    Ok(match op.layout.ty.kind() {
+      TyKind::Uint(_) => {
+        let value = self.ecx.read_scalar(&op)?.check_init()?.to_uint(size)?;
+        u64::try_from(value).ok()
+      }
+      TyKind::Int(_) => {
+        let value = self.ecx.read_scalar(&op)?.check_init()?.to_int(size)?;
+        u64::try_from(value).ok()
+      }
+      _ => None,
+    })
+  }
+
+  fn read_wrapped_usize(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, u64> {
+    self.read_usize(&self.unwrap_integer(op)?)
+  }
+
+  fn read_unsafe_cell(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, MValue> {
+    let (_, value_field) = op.field_by_name("value", &self.ecx)?;
+    Ok(self.read(&value_field))
+  }
+
+  pub(super) fn read_cell(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, value_field) = op.field_by_name("value", &self.ecx)?;
+    Ok(MValue::Cell(Box::new(self.read_unsafe_cell(&value_field)?)))
+  }
+
+  pub(super) fn read_refcell(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, borrow_field) = op.field_by_name("borrow", &self.ecx)?;
+    let flag = self.unwrap_integer(&borrow_field)?;
+    let flag = self
+      .ecx
+      .read_scalar(&flag)?
+      .check_init()?
+      .to_machine_isize(&self.ecx)?;
+    let state = match flag {
+      0 => MBorrowState::Unborrowed,
+      n if n > 0 => MBorrowState::Shared(n as u64),
+      _ => MBorrowState::Exclusive,
+    };
+
+    let (_, value_field) = op.field_by_name("value", &self.ecx)?;
+    Ok(MValue::RefCell {
+      value: Box::new(self.read_unsafe_cell(&value_field)?),
+      state,
+    })
+  }
+
+  /// Reads a `Mutex` or `RwLock`, assuming the futex-based implementations
+  /// that std uses on Linux.
+  pub(super) fn read_lock(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    kind: MLockKind,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, inner_field) = op.field_by_name("inner", &self.ecx)?;
+    let futex = self.unwrap_integer(&inner_field)?;
+    let futex = self.ecx.read_scalar(&futex)?.check_init()?.to_u32()?;
+    let state = match kind {
+      MLockKind::Mutex => match futex {
+        0 => MBorrowState::Unborrowed,
+        _ => MBorrowState::Exclusive,
+      },
+      MLockKind::RwLock => {
+        // The low 30 bits count readers, saturating to mean write-locked.
+        const MASK: u32 = (1 << 30) - 1;
+        match futex & MASK {
+          0 => MBorrowState::Unborrowed,
+          MASK => MBorrowState::Exclusive,
+          n => MBorrowState::Shared(n as u64),
+        }
+      }
+    };
+
+    let (_, poison_field) = op.field_by_name("poison", &self.ecx)?;
+    let poisoned = self.unwrap_integer(&poison_field)?;
+    let poisoned = self.ecx.read_scalar(&poisoned)?.check_init()?.to_bool()?;
+
+    let (_, data_field) = op.field_by_name("data", &self.ecx)?;
+    Ok(MValue::Lock {
+      kind,
+      value: Box::new(self.read_unsafe_cell(&data_field)?),
+      state,
+      poisoned,
+    })
+  }
+
+  pub(super) fn read_rc(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    kind: MRcKind,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, nonnull) = op.field_by_name("ptr", &self.ecx)?;
+    let (_, ptr) = nonnull.field_by_name("pointer", &self.ecx)?;
+
+    // A `Weak::new()` dangles, so there is no inner allocation to read.
+    let inner: OpTy<'tcx, miri::Tag> = match self.ecx.deref_operand(&ptr) {
+      Ok(place) => place.into(),
+      Err(_) => {
+        return Ok(MValue::Rc {
+          kind,
+          pointer: Box::new(MValue::Unallocated),
+          strong: 0,
+          weak: 0,
+        });
+      }
+    };
+
+    let (_, strong_field) = inner.field_by_name("strong", &self.ecx)?;
+    let strong = self.read_wrapped_usize(&strong_field)?;
+    let (_, weak_field) = inner.field_by_name("weak", &self.ecx)?;
+    let weak = self.read_wrapped_usize(&weak_field)?;
+
+    let value_name = match kind {
+      MRcKind::Rc | MRcKind::RcWeak => "value",
+      MRcKind::Arc | MRcKind::ArcWeak => "data",
+    };
+    let (_, value_field) = inner.field_by_name(value_name, &self.ecx)?;
+
+    // The strong pointers collectively hold one implicit weak reference,
+    // and the value has been dropped once the strong count reaches zero.
+    let (pointer, weak) = if strong > 0 {
+      let place = value_field.try_as_mplace().unwrap();
+      (self.pointer_to(place, MPointerKind::Shared)?, weak - 1)
+    } else {
+      (MValue::Unallocated, weak)
+    };
+
+    Ok(MValue::Rc {
+      kind,
+      pointer: Box::new(pointer),
+      strong,
+      weak,
+    })
+  }
+
+  fn type_arg(ty: Ty<'tcx>) -> Ty<'tcx> {
+    match ty.kind() {
+      TyKind::Adt(_, substs) => substs.type_at(0),
+      _ => unreachable!("{ty:?} has no type arguments"),
+    }
+  }
+
+  pub(super) fn assume_init(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, OpTy<'tcx, miri::Tag>> {
+    let (_, manually_drop) = op.field_by_name("value", &self.ecx)?;
+    let (_, value) = manually_drop.field_by_name("value", &self.ecx)?;
+    Ok(value)
+  }
+
+  /// Returns the full buckets of a `hashbrown::raw::RawTable`. Buckets are
+  /// stored back-to-front just before the control bytes, and a bucket is
+  /// full iff the high bit of its control byte is clear.
+  fn raw_table_buckets(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, Vec<OpTy<'tcx, miri::Tag>>> {
+    let elem_layout = self.ecx.layout_of(Self::type_arg(op.layout.ty))?;
+    let (_, inner) = op.field_by_name("table", &self.ecx)?;
+    let (_, items_field) = inner.field_by_name("items", &self.ecx)?;
+    if self.read_usize(&items_field)? == 0 {
+      return Ok(Vec::new());
+    }
+
+    let (_, mask_field) = inner.field_by_name("bucket_mask", &self.ecx)?;
+    let num_buckets = self.read_usize(&mask_field)? + 1;
+    let (_, ctrl_field) = inner.field_by_name("ctrl", &self.ecx)?;
+    let (_, ctrl_field) = ctrl_field.field_by_name("pointer", &self.ecx)?;
+    let ctrl = self.ecx.read_pointer(&ctrl_field)?;
+    let u8_layout = self.ecx.layout_of(self.tcx.types.u8)?;
+
+    let mut buckets = Vec::new();
+    for i in 0 .. num_buckets {
+      let ctrl_byte = MPlaceTy::from_aligned_ptr(
+        ctrl.wrapping_offset(Size::from_bytes(i), &self.ecx),
+        u8_layout,
+      );
+      let ctrl_byte = self
+        .ecx
+        .read_scalar(&ctrl_byte.into())?
+        .check_init()?
+        .to_u8()?;
+      if ctrl_byte & 0x80 == 0 {
+        let offset = -((elem_layout.size.bytes() * (i + 1)) as i64);
+        let bucket = MPlaceTy::from_aligned_ptr(
+          ctrl.wrapping_signed_offset(offset, &self.ecx),
+          elem_layout,
+        );
+        buckets.push(bucket.into());
+      }
+    }
+    Ok(buckets)
+  }
+
+  /// Returns the key-value pairs of a `BTreeMap` in order.
+  fn btree_entries(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, Vec<(OpTy<'tcx, miri::Tag>, OpTy<'tcx, miri::Tag>)>> {
+    let mut entries = Vec::new();
+    let (_, root) = op.field_by_name("root", &self.ecx)?;
+    let (_, variant_idx) = self.ecx.read_discriminant(&root)?;
+    let root_variant = root.layout.ty.ty_adt_def().unwrap().variant(variant_idx);
+    if root_variant.fields.is_empty() {
+      return Ok(entries);
+    }
+
+    let root = root
+      .project_downcast(&self.ecx, variant_idx)?
+      .project_field(&self.ecx, 0)?;
+    let (_, height_field) = root.field_by_name("height", &self.ecx)?;
+    let height = self.read_usize(&height_field)?;
+    let (_, node) = root.field_by_name("node", &self.ecx)?;
+    self.btree_node_entries(&node, height, &mut entries)?;
+    Ok(entries)
+  }
+
+  fn btree_node_entries(
+    &self,
+    node: &OpTy<'tcx, miri::Tag>,
+    height: u64,
+    entries: &mut Vec<(OpTy<'tcx, miri::Tag>, OpTy<'tcx, miri::Tag>)>,
+  ) -> InterpResult<'tcx, ()> {
+    let (_, ptr) = node.field_by_name("pointer", &self.ecx)?;
+    let leaf = self.ecx.deref_operand(&ptr)?;
+    let leaf_ptr = leaf.ptr;
+    let leaf_op: OpTy<'tcx, miri::Tag> = leaf.into();
+    let (_, len_field) = leaf_op.field_by_name("len", &self.ecx)?;
+    let len = self.ecx.read_scalar(&len_field)?.check_init()?.to_u16()? as u64;
+    let (_, keys) = leaf_op.field_by_name("keys", &self.ecx)?;
+    let (_, vals) = leaf_op.field_by_name("vals", &self.ecx)?;
+
+    // Internal nodes start with a leaf node, so the same pointer can be
+    // reinterpreted as an `InternalNode` found through the parent pointer type.
+    let edges = if height > 0 {
+      let (_, parent) = leaf_op.field_by_name("parent", &self.ecx)?;
+      let internal_ty = Self::type_arg(Self::type_arg(parent.layout.ty));
+      let internal_layout = self.ecx.layout_of(internal_ty)?;
+      let internal: OpTy<'tcx, miri::Tag> =
+        MPlaceTy::from_aligned_ptr(leaf_ptr, internal_layout).into();
+      Some(internal.field_by_name("edges", &self.ecx)?.1)
+    } else {
+      None
+    };
+
+    for i in 0 ..= len {
+      if let Some(edges) = &edges {
+        let edge = self.assume_init(&self.ecx.operand_index(edges, i)?)?;
+        self.btree_node_entries(&edge, height - 1, entries)?;
+      }
+      if i < len {
+        let key = self.assume_init(&self.ecx.operand_index(&keys, i)?)?;
+        let val = self.assume_init(&self.ecx.operand_index(&vals, i)?)?;
+        entries.push((key, val));
+      }
+    }
+    Ok(())
+  }
+
+  /// Maps are truncated with an entry whose key and value are both the marker.
+  fn truncated_entry(truncated: Option<MValue>) -> Option<(MValue, MValue)> {
+    let remaining = match truncated? {
+      MValue::Truncated { remaining } => remaining,
+      _ => unreachable!(),
+    };
+    Some((MValue::Truncated { remaining }, MValue::Truncated {
+      remaining,
+    }))
+  }
+
+  pub(super) fn read_hash_map(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, base) = op.field_by_name("base", &self.ecx)?;
+    let (_, table) = base.field_by_name("table", &self.ecx)?;
+    let buckets = self.raw_table_buckets(&table)?;
+    let (len, truncated) = self.limit(buckets.len() as u64);
+    let mut entries = buckets
+      .into_iter()
+      .take(len as usize)
+      .map(|bucket| {
+        let key = self.read(&bucket.project_field(&self.ecx, 0)?);
+        let val = self.read(&bucket.project_field(&self.ecx, 1)?);
+        Ok((key, val))
+      })
+      .collect::<InterpResult<'tcx, Vec<_>>>()?;
+    entries.extend(Self::truncated_entry(truncated));
+    Ok(MValue::Map { entries })
+  }
+
+  pub(super) fn read_hash_set(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, base) = op.field_by_name("base", &self.ecx)?;
+    let (_, map) = base.field_by_name("map", &self.ecx)?;
+    let (_, table) = map.field_by_name("table", &self.ecx)?;
+    let buckets = self.raw_table_buckets(&table)?;
+    let (len, truncated) = self.limit(buckets.len() as u64);
+    let mut elements = buckets
+      .into_iter()
+      .take(len as usize)
+      .map(|bucket| Ok(self.read(&bucket.project_field(&self.ecx, 0)?)))
+      .collect::<InterpResult<'tcx, Vec<_>>>()?;
+    elements.extend(truncated);
+    Ok(MValue::Set { elements })
+  }
+
+  pub(super) fn read_btree_map(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let btree_entries = self.btree_entries(op)?;
+    let (len, truncated) = self.limit(btree_entries.len() as u64);
+    let mut entries = btree_entries
+      .into_iter()
+      .take(len as usize)
+      .map(|(key, val)| (self.read(&key), self.read(&val)))
+      .collect::<Vec<_>>();
+    entries.extend(Self::truncated_entry(truncated));
+    Ok(MValue::Map { entries })
+  }
+
+  pub(super) fn read_btree_set(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let (_, map) = op.field_by_name("map", &self.ecx)?;
+    let btree_entries = self.btree_entries(&map)?;
+    let (len, truncated) = self.limit(btree_entries.len() as u64);
+    let mut elements = btree_entries
+      .into_iter()
+      .take(len as usize)
+      .map(|(key, _)| self.read(&key))
+      .collect::<Vec<_>>();
+    elements.extend(truncated);
+    Ok(MValue::Set { elements })
+  }
+
+  pub(super) fn read_deque(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    // `tail` is the physical index of the front of the deque and `head` is
+    // one past its back.
+    let (_, tail_field) = op.field_by_name("tail", &self.ecx)?;
+    let tail = self.read_usize(&tail_field)?;
+    let (_, head_field) = op.field_by_name("head", &self.ecx)?;
+    let head = self.read_usize(&head_field)?;
+    let (_, buf_field) = op.field_by_name("buf", &self.ecx)?;
+    let (_, cap_field) = buf_field.field_by_name("cap", &self.ecx)?;
+    let capacity = self.read_usize(&cap_field)?;
+
+    let mask = capacity.saturating_sub(1);
+    let (len, truncated) = self.limit(head.wrapping_sub(tail) & mask);
+    let mut elements = match self.deref_raw_vec(&buf_field)? {
+      Some(place) => (0 .. len)
+        .map(|i| {
+          let offset = place.layout.size * ((tail + i) & mask);
+          let elem_place =
+            place.offset(offset, MemPlaceMeta::None, place.layout, &self.ecx)?;
+          Ok(self.read(&elem_place.into()))
+        })
+        .collect::<InterpResult<'tcx, Vec<_>>>()?,
+      None => Vec::new(),
+    };
+    elements.extend(truncated);
+
+    Ok(MValue::Deque {
+      elements,
+      head: tail,
+      capacity,
+    })
+  }
+
+  fn read_fields<'a>(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    fields: impl Iterator<Item = &'a FieldDef>,
+  ) -> InterpResult<'tcx, Vec<MField>> {
+    fields
+      .enumerate()
+      .map(|(i, field)| self.read_field(op, i, field))
+      .collect()
+  }
+
+  pub(super) fn read_field(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    i: usize,
+    field: &FieldDef,
+  ) -> InterpResult<'tcx, MField> {
+    let field_op = op.project_field(&self.ecx, i)?;
+    Ok(MField {
+      name: field.name.to_ident_string(),
+      offset: op.layout.fields.offset(i).bytes(),
+      value: self.read(&field_op),
+    })
+  }
+
+  /// Builds a struct from already-read fields, with the layout of `op`.
+  pub(super) fn make_struct(
+    &self,
+    op: &OpTy<'tcx, miri::Tag>,
+    name: String,
+    fields: Vec<MField>,
+  ) -> MValue {
+    MValue::Struct {
+      name,
+      ty: op.layout.ty.to_string(),
+      size: op.layout.size.bytes(),
+      align: op.layout.align.abi.bytes(),
+      fields,
+    }
+  }
+
+  /// Reads `op` into an `MValue`. A value that cannot be read, e.g. a
+  /// `*const bool` pointing at an invalid byte, becomes `MValue::Opaque` with
+  /// a warning.
+  pub(super) fn read(&self, op: &OpTy<'tcx, miri::Tag>) -> MValue {
+    let depth = self.depth.get();
+    if depth >= self.limits.max_depth {
+      return MValue::TooDeep;
+    }
+
+    self.depth.set(depth + 1);
+    let value = self.read_value(op);
+    self.depth.set(depth);
+    value.unwrap_or_else(|error| self.unreadable(op, error))
+  }
+
+  /// Returns true if none of the bytes of `op` are initialized. Partially
+  /// initialized values are read field by field instead.
+  fn is_uninit(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, bool> {
+    let mplace = match op.try_as_mplace() {
+      Ok(mplace) => mplace,
+      Err(imm) => {
+        return Ok(matches!(
+          *imm,
+          Immediate::Scalar(ScalarMaybeUninit::Uninit)
+            | Immediate::ScalarPair(ScalarMaybeUninit::Uninit, ScalarMaybeUninit::Uninit)
+        ));
+      }
+    };
+    if mplace.layout.is_unsized() || mplace.layout.is_zst() {
+      return Ok(false);
+    }
+
+    let (alloc_id, offset) = match self.place_alloc(&mplace) {
+      Some(location) => location,
+      None => return Ok(false),
+    };
+    let alloc = self.ecx.get_alloc_raw(alloc_id)?;
+    let size = mplace.layout.size;
+    Ok(
+      match alloc
+        .init_mask()
+        .is_range_initialized(offset, offset + size)
+      {
+        Ok(()) => false,
+        Err(uninit) => uninit.start == offset && uninit.size == size,
+      },
+    )
+  }
+
+  fn read_value(&self, op: &OpTy<'tcx, miri::Tag>) -> InterpResult<'tcx, MValue> {
+    if self.is_uninit(op)? {
+      return Ok(MValue::Uninit);
+    }
+
     let ty = op.layout.ty;
 
     Ok(match ty.kind() {
-      TyKind::Adt(adt_def, _subst) => match adt_def.adt_kind() {
-        AdtKind::Struct => {
-          let def_id = adt_def.did();
-          let name = self.tcx.item_name(def_id).to_ident_string();
-
-          match self.type_def_ids.get_path(def_id) {
-            Some(path) => match path.as_str() {
-              "std::vec::Vec" => {
-                let (_, len_field) = op.field_by_name("len", &self.ecx)?;
-                let len = match self.read(&len_field)? {
-                  MValue::Uint(n) => n,
-                  _ => unreachable!(),
-                };
-                let (_, buf_field) = op.field_by_name("buf", &self.ecx)?;
-                let contents = self.read_raw_vec(&buf_field, len)?;
-                MValue::Vec(contents)
-              }
-              _ => todo!(),
-            },
-            None => {
-              let fields = adt_def
-                .all_fields()
-                .enumerate()
-                .map(|(i, field)| {
-                  let field_op = op.project_field(&self.ecx, i)?;
-                  let field_val = self.read(&field_op)?;
-                  Ok((field.name.to_ident_string(), field_val))
-                })
-                .collect::<InterpResult<'tcx, Vec<_>>>()?;
-
-              MValue::Struct { name, fields }
+      TyKind::Adt(adt_def, _subst) => match self.type_def_ids.get_renderer(adt_def.did())
+      {
+        Some(renderer) => renderer.render(self, op)?,
+        None => match adt_def.adt_kind() {
+          AdtKind::Struct => {
+            let name = self.tcx.item_name(adt_def.did()).to_ident_string();
+            let fields = self.read_fields(op, adt_def.all_fields())?;
+            self.make_struct(op, name, fields)
+          }
+          AdtKind::Enum => {
+            let name = self.tcx.item_name(adt_def.did()).to_ident_string();
+            let (_, variant_idx) = self.ecx.read_discriminant(op)?;
+            let variant_def = adt_def.variant(variant_idx);
+            let variant_op = op.project_downcast(&self.ecx, variant_idx)?;
+            let fields = self.read_fields(&variant_op, variant_def.fields.iter())?;
+            MValue::Enum {
+              name,
+              variant: variant_def.name.to_ident_string(),
+              fields,
             }
           }
-        }
-        _ => todo!(),
+          AdtKind::Union => self.opaque(op),
+        },
       },
 
       _ if ty.is_primitive() => {
         let imm = self.ecx.read_immediate(op)?;
         let scalar = match &*imm {
-          Immediate::Scalar(scalar) => scalar.check_init()?,
+          Immediate::Scalar(ScalarMaybeUninit::Scalar(scalar)) => *scalar,
+          Immediate::Scalar(ScalarMaybeUninit::Uninit) => return Ok(MValue::Uninit),
           _ => unreachable!(),
         };
         match ty.kind() {
           TyKind::Bool => MValue::Bool(scalar.to_bool()?),
           TyKind::Char => MValue::Char(scalar.to_char()?.to_string()),
-          TyKind::Uint(uty) => MValue::Uint(match uty.bit_width() {
-            Some(width) => scalar.to_uint(Size::from_bits(width))? as u64,
-            None => scalar.to_machine_usize(&self.ecx)? as u64,
-          }),
-          TyKind::Int(ity) => MValue::Int(match ity.bit_width() {
-            Some(width) => scalar.to_int(Size::from_bits(width))? as i64,
-            None => scalar.to_machine_isize(&self.ecx)? as i64,
-          }),
-          TyKind::Float(fty) => MValue::Float(match fty {
-            FloatTy::F32 => f32::from_bits(scalar.to_f32()?.to_bits() as u32) as f64,
-            FloatTy::F64 => f64::from_bits(scalar.to_f64()?.to_bits() as u64),
-          }),
+          // Integers are printed as decimal strings so that 128-bit integers
+          // (and 64-bit integers in JavaScript) are not truncated.
+          TyKind::Uint(_) => MValue::Uint {
+            ty: ty.to_string(),
+            value: scalar.to_uint(op.layout.size)?.to_string(),
+          },
+          TyKind::Int(_) => MValue::Int {
+            ty: ty.to_string(),
+            value: scalar.to_int(op.layout.size)?.to_string(),
+          },
+          TyKind::Float(fty) => MValue::Float {
+            ty: ty.to_string(),
+            value: MFloat::from(match fty {
+              FloatTy::F32 => f32::from_bits(scalar.to_f32()?.to_bits() as u32) as f64,
+              FloatTy::F64 => f64::from_bits(scalar.to_f64()?.to_bits() as u64),
+            }),
+          },
           _ => unreachable!(),
         }
       }
 
+      TyKind::Tuple(tys) => MValue::Tuple(
+        (0 .. tys.len())
+          .map(|i| Ok(self.read(&op.project_field(&self.ecx, i)?)))
+          .collect::<InterpResult<'tcx, Vec<_>>>()?,
+      ),
+
+      TyKind::Array(elem_ty, _) => {
+        let len = op.len(&self.ecx)?;
+        let (shown, truncated) = self.limit(len);
+        let mut elements = (0 .. shown)
+          .map(|i| Ok(self.read(&self.ecx.operand_index(op, i)?)))
+          .collect::<InterpResult<'tcx, Vec<_>>>()?;
+        elements.extend(truncated);
+        MValue::Array {
+          elem_ty: elem_ty.to_string(),
+          len,
+          elements,
+        }
+      }
+
+      TyKind::Slice(elem_ty) => {
+        let place = op.try_as_mplace().unwrap();
+        let len = place.len(&self.ecx)?;
+        let elem_layout = self.ecx.layout_of(*elem_ty)?;
+        MValue::Array {
+          elem_ty: elem_ty.to_string(),
+          len,
+          elements: self.read_elements(&place, elem_layout, len)?,
+        }
+      }
+
       _ if ty.is_str() => {
-        MValue::String(self.ecx.read_str(&op.try_as_mplace().unwrap())?.to_string())
+        let place = op.try_as_mplace().unwrap();
+        let len = place.len(&self.ecx)?;
+        let (value, valid_utf8, remaining) = self.read_utf8(&place, len)?;
+        MValue::String {
+          value,
+          len,
+          capacity: None,
+          buffer: None,
+          valid_utf8,
+          remaining,
+        }
+      }
+
+      TyKind::Ref(_, _, Mutability::Mut) => {
+        self.read_pointer(op, MPointerKind::RefMut)?
       }
+      TyKind::Ref(..) => self.read_pointer(op, MPointerKind::Ref)?,
+      TyKind::RawPtr(_) => self.read_pointer(op, MPointerKind::Raw)?,
 
-      _ if ty.is_any_ptr() => match self.ecx.deref_operand(op) {
-        Ok(mplace) => self.read(&mplace.into())?,
-        Err(_) => MValue::Unallocated,
+      TyKind::FnPtr(_) => self.read_fn_ptr(op)?,
+      TyKind::FnDef(def_id, _) => MValue::FnPtr {
+        name: self.tcx.def_path_str(*def_id),
       },
+      TyKind::Closure(def_id, _) => self.read_closure(op, *def_id)?,
+      TyKind::Dynamic(..) => self.read_dyn(op)?,
 
-      kind => todo!("{:?} / {:?}", **op, kind),
+      _ => self.opaque(op),
     })
   }
 }
@@ -0,0 +1,205 @@
+use miri::{InterpResult, OpTy};
+use rustc_middle::ty::TyKind;
+use rustc_span::sym;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  eval::VisEvaluator,
+  mvalue::{MLockKind, MPointerKind, MRcKind, MValue, OpTyExt},
+};
+
+/// Reads values of a particular type in place of the default structural view.
+///
+/// Renderers are registered against a path, see [`TypeDefIds`](crate::TypeDefIds).
+pub trait ValueRenderer {
+  fn render<'hir, 'mir, 'tcx: 'hir>(
+    &self,
+    evaluator: &VisEvaluator<'hir, 'mir, 'tcx>,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue>;
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Builtin {
+  Vec,
+  String,
+  Box,
+  Rc(MRcKind),
+  Cell,
+  RefCell,
+  Lock(MLockKind),
+  HashMap,
+  HashSet,
+  BTreeMap,
+  BTreeSet,
+  VecDeque,
+  MaybeUninit,
+}
+
+pub(crate) const BUILTINS: &[(&str, Builtin)] = &[
+  ("std::vec::Vec", Builtin::Vec),
+  ("std::string::String", Builtin::String),
+  ("std::boxed::Box", Builtin::Box),
+  ("std::rc::Rc", Builtin::Rc(MRcKind::Rc)),
+  ("std::rc::Weak", Builtin::Rc(MRcKind::RcWeak)),
+  ("std::sync::Arc", Builtin::Rc(MRcKind::Arc)),
+  ("std::sync::Weak", Builtin::Rc(MRcKind::ArcWeak)),
+  ("std::cell::Cell", Builtin::Cell),
+  ("std::cell::RefCell", Builtin::RefCell),
+  ("std::sync::Mutex", Builtin::Lock(MLockKind::Mutex)),
+  ("std::sync::RwLock", Builtin::Lock(MLockKind::RwLock)),
+  ("std::collections::HashMap", Builtin::HashMap),
+  ("std::collections::HashSet", Builtin::HashSet),
+  ("std::collections::BTreeMap", Builtin::BTreeMap),
+  ("std::collections::BTreeSet", Builtin::BTreeSet),
+  ("std::collections::VecDeque", Builtin::VecDeque),
+  ("std::mem::MaybeUninit", Builtin::MaybeUninit),
+];
+
+impl ValueRenderer for Builtin {
+  fn render<'hir, 'mir, 'tcx: 'hir>(
+    &self,
+    evaluator: &VisEvaluator<'hir, 'mir, 'tcx>,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    match *self {
+      Builtin::Vec => evaluator.read_vec(op),
+      Builtin::String => evaluator.read_string(op),
+      Builtin::Box => evaluator.read_pointer(op, MPointerKind::Box),
+      Builtin::Rc(kind) => evaluator.read_rc(op, kind),
+      Builtin::Cell => evaluator.read_cell(op),
+      Builtin::RefCell => evaluator.read_refcell(op),
+      Builtin::Lock(kind) => evaluator.read_lock(op, kind),
+      Builtin::HashMap => evaluator.read_hash_map(op),
+      Builtin::HashSet => evaluator.read_hash_set(op),
+      Builtin::BTreeMap => evaluator.read_btree_map(op),
+      Builtin::BTreeSet => evaluator.read_btree_set(op),
+      Builtin::VecDeque => evaluator.read_deque(op),
+      Builtin::MaybeUninit => Ok(evaluator.read(&evaluator.assume_init(op)?)),
+    }
+  }
+}
+
+/// A renderer declared by a course author in `mirivis.json`, e.g.
+///
+/// ```json
+/// { "path": "crate::Matrix", "view": { "type": "Grid", "elements": "data", "width": "cols" } }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RendererConfig {
+  /// Path to the type, resolved from the root of the traced crate.
+  pub path: String,
+  pub view: CustomView,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum CustomView {
+  /// Lays out the sequence in field `elements` as rows of length `width`,
+  /// where `width` names an integer field of the same struct.
+  Grid { elements: String, width: String },
+
+  /// Only shows the `pub` fields of a struct.
+  PublicFields,
+
+  /// Shows every field of a struct except the given ones.
+  HideFields { fields: Vec<String> },
+}
+
+impl ValueRenderer for CustomView {
+  fn render<'hir, 'mir, 'tcx: 'hir>(
+    &self,
+    evaluator: &VisEvaluator<'hir, 'mir, 'tcx>,
+    op: &OpTy<'tcx, miri::Tag>,
+  ) -> InterpResult<'tcx, MValue> {
+    let adt_def = match op.layout.ty.kind() {
+      TyKind::Adt(adt_def, _) if adt_def.is_struct() => *adt_def,
+      _ => return Ok(evaluator.opaque(op)),
+    };
+    let name = evaluator.tcx.item_name(adt_def.did()).to_ident_string();
+
+    match self {
+      CustomView::Grid { elements, width } => {
+        let field_index = |field_name: &str| {
+          adt_def
+            .all_fields()
+            .position(|field| field.name.as_str() == field_name)
+        };
+        let (width_index, elements_index) =
+          match (field_index(width), field_index(elements)) {
+            (Some(width_index), Some(elements_index)) => (width_index, elements_index),
+            _ => {
+              evaluator.warn(format!(
+                "Grid view of `{name}` needs fields `{elements}` and `{width}`"
+              ));
+              return Ok(evaluator.opaque(op));
+            }
+          };
+
+        let width_field = op.project_field(&evaluator.ecx, width_index)?;
+        let width = match evaluator.read_index(&width_field)? {
+          Some(width) => width as usize,
+          None => {
+            evaluator.warn(format!(
+              "Grid view of `{name}` needs field `{width}` to be a non-negative integer"
+            ));
+            return Ok(evaluator.opaque(op));
+          }
+        };
+        let elements_field = op.project_field(&evaluator.ecx, elements_index)?;
+        let elements = match elements_field.layout.ty.kind() {
+          TyKind::Adt(adt_def, _)
+            if evaluator.tcx.is_diagnostic_item(sym::Vec, adt_def.did()) =>
+          {
+            evaluator.read_vec_elements(&elements_field)?
+          }
+          TyKind::Array(..) => match evaluator.read(&elements_field) {
+            MValue::Array { elements, .. } => elements,
+            value => return Ok(value),
+          },
+          _ => {
+            evaluator.warn(format!(
+              "Grid view of `{name}` needs field `{elements}` to be a `Vec` or an array"
+            ));
+            return Ok(evaluator.opaque(op));
+          }
+        };
+
+        let mut rows = Vec::new();
+        let mut elements = elements.into_iter().peekable();
+        while elements.peek().is_some() {
+          rows.push(elements.by_ref().take(width.max(1)).collect());
+        }
+        Ok(MValue::Grid { name, rows })
+      }
+
+      CustomView::PublicFields | CustomView::HideFields { .. } => {
+        if let CustomView::HideFields { fields } = self {
+          for hidden in fields {
+            if !adt_def
+              .all_fields()
+              .any(|field| field.name.as_str() == hidden)
+            {
+              evaluator.warn(format!(
+                "Cannot hide field `{hidden}`, which `{name}` does not have"
+              ));
+            }
+          }
+        }
+
+        let fields = adt_def
+          .all_fields()
+          .enumerate()
+          .filter(|(_, field)| match self {
+            CustomView::HideFields { fields } => {
+              !fields.iter().any(|hidden| field.name.as_str() == hidden)
+            }
+            _ => field.vis.is_public(),
+          })
+          .map(|(i, field)| evaluator.read_field(op, i, field))
+          .collect::<InterpResult<'tcx, Vec<_>>>()?;
+        Ok(evaluator.make_struct(op, name, fields))
+      }
+    }
+  }
+}
@@ -0,0 +1,157 @@
+use std::ops::RangeInclusive;
+
+use rustc_ast::AttrKind;
+use rustc_hir::def_id::LocalDefId;
+use rustc_middle::ty::TyCtxt;
+use rustc_span::FileName;
+
+use crate::eval::{is_user_file, Frame};
+
+const START_MARKER: &str = "// situ:start";
+const STOP_MARKER: &str = "// situ:stop";
+
+/// The parts of the program marked by the author for tracing, either as
+/// functions with `#[situ::trace]`, or as lines between `// situ:start` and
+/// `// situ:stop` comments.
+///
+/// If nothing is marked then the whole program is traced.
+pub(crate) struct TraceScope {
+  functions: Vec<LocalDefId>,
+  regions: Vec<(FileName, RangeInclusive<usize>)>,
+}
+
+fn is_trace_attr(kind: &AttrKind) -> bool {
+  match kind {
+    AttrKind::Normal(item, _) => item
+      .path
+      .segments
+      .iter()
+      .map(|segment| segment.ident.name.as_str())
+      .eq(["situ", "trace"]),
+    _ => false,
+  }
+}
+
+/// Returns the lines between `// situ:start` and `// situ:stop` markers.
+/// Lines are 1-indexed, as in `Loc::line`. Regions do not nest, so a start
+/// marker inside a region is ignored, as is a stop marker outside of one. A
+/// region without a stop marker runs to the end of the file.
+fn marked_regions(src: &str) -> Vec<RangeInclusive<usize>> {
+  let mut regions = Vec::new();
+  let mut start = None;
+  for (i, line) in src.lines().enumerate() {
+    match line.trim() {
+      START_MARKER => {
+        start.get_or_insert(i + 1);
+      }
+      STOP_MARKER => {
+        if let Some(start) = start.take() {
+          regions.push(start ..= i + 1);
+        }
+      }
+      _ => {}
+    }
+  }
+  if let Some(start) = start {
+    regions.push(start ..= usize::MAX);
+  }
+  regions
+}
+
+impl TraceScope {
+  pub(crate) fn new(tcx: TyCtxt) -> Self {
+    let hir = tcx.hir();
+    let functions = hir
+      .body_owners()
+      .filter(|def_id| {
+        let hir_id = hir.local_def_id_to_hir_id(*def_id);
+        hir
+          .attrs(hir_id)
+          .iter()
+          .any(|attr| is_trace_attr(&attr.kind))
+      })
+      .collect();
+
+    let mut regions = Vec::new();
+    for file in tcx.sess.source_map().files().iter() {
+      if !is_user_file(file) {
+        continue;
+      }
+      let src = match &file.src {
+        Some(src) => src,
+        None => continue,
+      };
+
+      regions.extend(
+        marked_regions(src)
+          .into_iter()
+          .map(|lines| (file.name.clone(), lines)),
+      );
+    }
+
+    TraceScope { functions, regions }
+  }
+
+  /// Returns true if steps at the current point of execution should be
+  /// emitted: the innermost frame is in a marked region, or any frame on the
+  /// stack is a marked function.
+  pub(crate) fn contains<'tcx>(
+    &self,
+    tcx: TyCtxt<'tcx>,
+    stack: &[Frame<'_, 'tcx>],
+  ) -> bool {
+    if self.functions.is_empty() && self.regions.is_empty() {
+      return true;
+    }
+
+    let in_function = stack.iter().any(|frame| {
+      matches!(frame.instance.def_id().as_local(), Some(def_id) if self.functions.contains(&def_id))
+    });
+
+    let in_region = stack.last().map_or(false, |frame| {
+      let loc = tcx
+        .sess
+        .source_map()
+        .lookup_char_pos(frame.current_span().lo());
+      self
+        .regions
+        .iter()
+        .any(|(file, lines)| *file == loc.file.name && lines.contains(&loc.line))
+    });
+
+    in_function || in_region
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn marked_regions_test() {
+    let src = "fn main() {\n  // situ:start\n  let x = 1;\n  // situ:stop\n}\n";
+    assert_eq!(marked_regions(src), vec![2 ..= 4]);
+
+    let src = "fn main() {\n  let x = 1;\n}\n";
+    assert!(marked_regions(src).is_empty());
+  }
+
+  #[test]
+  fn unterminated_region_test() {
+    let src = "fn main() {\n  // situ:start\n  let x = 1;\n}\n";
+    assert_eq!(marked_regions(src), vec![2 ..= usize::MAX]);
+  }
+
+  #[test]
+  fn stop_without_start_test() {
+    let src = "// situ:stop\nfn main() {\n  // situ:start\n  let x = 1;\n  // situ:stop\n  // situ:stop\n}\n";
+    assert_eq!(marked_regions(src), vec![3 ..= 5]);
+  }
+
+  #[test]
+  fn nested_start_test() {
+    let src =
+      "// situ:start\nfn main() {\n  // situ:start\n  let x = 1;\n  // situ:stop\n}\n";
+    assert_eq!(marked_regions(src), vec![1 ..= 5]);
+  }
+}
@@ -0,0 +1 @@
+../../../../../backend/crates/mirivis/bindings/MBytes.ts
\ No newline at end of file
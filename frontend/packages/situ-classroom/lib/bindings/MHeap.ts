@@ -0,0 +1 @@
+../../../../../backend/crates/mirivis/bindings/MHeap.ts
\ No newline at end of file
@@ -0,0 +1 @@
+../../../../../backend/crates/mirivis/bindings/MTermination.ts
\ No newline at end of file
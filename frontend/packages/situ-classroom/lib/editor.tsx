@@ -138,8 +138,14 @@ export let Editor = observer(() => {
       () => visualizer.step,
       step => {
         if (step != -1) {
-          let frame = visualizer.output![step];
-          editor.dispatch({ effects: [setVisualizerRanges.of(frame.ranges)] });
+          let { stack } = visualizer.output!.steps[step];
+          let frame = stack[stack.length - 1];
+          // Ranges can point into any file of the crate, so only show the ones
+          // in the open file.
+          let ranges = frame.ranges
+            .filter(range => state.path !== null && state.path.endsWith(range.file))
+            .map(({ char_start, char_end }): [number, number] => [char_start, char_end]);
+          editor.dispatch({ effects: [setVisualizerRanges.of(ranges)] });
         }
       }
     );
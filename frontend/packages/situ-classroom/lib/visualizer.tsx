@@ -3,56 +3,101 @@ import { observer } from "mobx-react";
 import React, { useContext, useEffect } from "react";
 
 import { ClientMessage } from "./bindings/ClientMessage";
+import { MBytes } from "./bindings/MBytes";
 import { MFrame } from "./bindings/MFrame";
+import { MHeap } from "./bindings/MHeap";
 import { MOutput } from "./bindings/MOutput";
+import { MTermination } from "./bindings/MTermination";
 import { MValue } from "./bindings/MValue";
 import { ClientContext } from "./client";
 
-let decode_string = (value: MValue & { type: "Struct" }): string => {
-  let vec = value.value.fields[0][1] as MValue & { type: "Vec" };
-  let buffer = new Uint8Array(vec.value.length);
-  vec.value.forEach((v, i) => {
-    let n = v as MValue & { type: "Uint" };
-    console.log(n.value);
-    buffer[i] = n.value as any;
-  });
-  let decoder = new TextDecoder();
-  let contents = decoder.decode(buffer);
-  return contents;
-};
-
 let Value: React.FC<{ value: MValue }> = ({ value }) => {
-  if (value.type == "Bool" || value.type == "Uint" || value.type == "Int") {
+  if (value.type == "Bool") {
     return <code>{value.value.toString()}</code>;
+  } else if (value.type == "Uint" || value.type == "Int") {
+    return <code title={value.value.ty}>{value.value.value}</code>;
   } else if (value.type == "Char") {
     return <code>'{value.value}'</code>;
   } else if (value.type == "Float") {
-    return <code>{value.value.toPrecision(3)}</code>;
+    let float = value.value.value;
+    let contents =
+      float.type == "Finite"
+        ? float.value.toPrecision(3)
+        : float.type == "NaN"
+        ? "NaN"
+        : float.type == "Infinity"
+        ? "inf"
+        : "-inf";
+    return <code title={value.value.ty}>{contents}</code>;
   } else if (value.type == "String") {
-    return <code>"{value.value}"</code>;
+    let { value: contents, remaining } = value.value;
+    return (
+      <code>
+        "{contents}"{remaining > 0 ? ` … ${remaining} more bytes` : null}
+      </code>
+    );
   } else if (value.type == "Struct") {
-    let { name, fields } = value.value;
-    if (name == "String") {
-      let contents = decode_string(value);
-      return <code>"{contents}"</code>;
-    } else {
-      return (
-        <>
-          <code>{name}(</code>
-          {fields.map(([key, value], i) => (
-            <>
-              <code>{key}</code>: <Value key={i} value={value} />,{" "}
-            </>
-          ))}
-          <code>)</code>
-        </>
-      );
-    }
+    let { name, ty, size, align, fields } = value.value;
+    return (
+      <>
+        <code title={`${ty} (size ${size}, align ${align})`}>{name}(</code>
+        {fields.map(({ name, offset, value }, i) => (
+          <>
+            <code title={`offset ${offset}`}>{name}</code>: <Value key={i} value={value} />
+            ,{" "}
+          </>
+        ))}
+        <code>)</code>
+      </>
+    );
+  } else if (value.type == "Enum") {
+    let { variant, fields } = value.value;
+    return (
+      <>
+        <code>{variant}</code>
+        {fields.length > 0 ? (
+          <>
+            <code>(</code>
+            {fields.map(({ value }, i) => (
+              <>
+                <Value key={i} value={value} />
+                {i < fields.length - 1 ? ", " : null}
+              </>
+            ))}
+            <code>)</code>
+          </>
+        ) : null}
+      </>
+    );
+  } else if (value.type == "Tuple") {
+    return (
+      <>
+        (
+        {value.value.map((value, i) => (
+          <>
+            <Value key={i} value={value} />,{" "}
+          </>
+        ))}
+        )
+      </>
+    );
   } else if (value.type == "Vec") {
+    let { len, capacity, buffer, reallocated } = value.value;
+    return (
+      <code title={`len ${len}, capacity ${capacity}`}>
+        vec{" "}
+        {buffer !== null ? (
+          <span className={reallocated ? "reallocated" : undefined}>#{buffer}</span>
+        ) : (
+          "[]"
+        )}
+      </code>
+    );
+  } else if (value.type == "Array") {
     return (
       <>
         [
-        {value.value.map((value, i) => (
+        {value.value.elements.map((value, i) => (
           <>
             <Value key={i} value={value} />,{" "}
           </>
@@ -60,6 +105,123 @@ let Value: React.FC<{ value: MValue }> = ({ value }) => {
         ]
       </>
     );
+  } else if (value.type == "Pointer") {
+    let { alloc_id, offset, kind } = value.value;
+    let prefix = kind == "Ref" ? "&" : kind == "RefMut" ? "&mut " : kind == "Box" ? "box " : "*";
+    return (
+      <code>
+        {prefix}#{alloc_id}
+        {offset > 0 ? `+${offset}` : null}
+      </code>
+    );
+  } else if (value.type == "Rc") {
+    let { kind, pointer, strong, weak } = value.value;
+    return (
+      <>
+        <code>
+          {kind}(strong={strong}, weak={weak})
+        </code>{" "}
+        <Value value={pointer} />
+      </>
+    );
+  } else if (value.type == "Map") {
+    return (
+      <>
+        {"{"}
+        {value.value.entries.map(([key, value], i) => (
+          <>
+            <Value key={`k${i}`} value={key} />: <Value key={`v${i}`} value={value} />,{" "}
+          </>
+        ))}
+        {"}"}
+      </>
+    );
+  } else if (value.type == "Set" || value.type == "Deque") {
+    let [open, close] = value.type == "Set" ? ["{", "}"] : ["[", "]"];
+    return (
+      <>
+        {open}
+        {value.value.elements.map((value, i) => (
+          <>
+            <Value key={i} value={value} />,{" "}
+          </>
+        ))}
+        {close}
+      </>
+    );
+  } else if (value.type == "Grid") {
+    return (
+      <table className="grid">
+        {value.value.rows.map((row, i) => (
+          <tr key={i}>
+            {row.map((value, j) => (
+              <td key={j}>
+                <Value value={value} />
+              </td>
+            ))}
+          </tr>
+        ))}
+      </table>
+    );
+  } else if (value.type == "Cell") {
+    return (
+      <>
+        <code>Cell(</code>
+        <Value value={value.value} />
+        <code>)</code>
+      </>
+    );
+  } else if (value.type == "RefCell" || value.type == "Lock") {
+    let name = value.type == "RefCell" ? "RefCell" : value.value.kind;
+    let { state } = value.value;
+    let stateStr =
+      state.type == "Unborrowed"
+        ? "unborrowed"
+        : state.type == "Shared"
+        ? `${state.value} shared`
+        : "exclusive";
+    return (
+      <>
+        <code>
+          {name}[{stateStr}](
+        </code>
+        <Value value={value.value.value} />
+        <code>)</code>
+      </>
+    );
+  } else if (value.type == "Closure") {
+    let { range, upvars } = value.value;
+    let location =
+      range !== null ? `${range.file}:${range.start_line}:${range.start_col}` : undefined;
+    return (
+      <>
+        <code title={location}>closure[</code>
+        {upvars.map(([name, value], i) => (
+          <>
+            <code>{name}</code>: <Value key={i} value={value} />
+            {i < upvars.length - 1 ? ", " : null}
+          </>
+        ))}
+        <code>]</code>
+      </>
+    );
+  } else if (value.type == "FnPtr") {
+    return <code>fn {value.value.name}</code>;
+  } else if (value.type == "Dyn") {
+    return (
+      <>
+        <code title={value.value.dyn_ty}>{value.value.ty}</code>{" "}
+        <Value value={value.value.value} />
+      </>
+    );
+  } else if (value.type == "Truncated") {
+    return <code>… {value.value.remaining} more</code>;
+  } else if (value.type == "TooDeep") {
+    return <code title="Nested too deeply to show">…</code>;
+  } else if (value.type == "Opaque") {
+    return <code>&lt;{value.value.type_name}&gt;</code>;
+  } else if (value.type == "Uninit") {
+    return <code className="uninit">uninit</code>;
   } else if (value.type == "Unallocated") {
     return <>💀</>;
   } else {
@@ -67,22 +229,115 @@ let Value: React.FC<{ value: MValue }> = ({ value }) => {
   }
 };
 
+let Bytes: React.FC<{ bytes: MBytes }> = ({ bytes: { bytes, remaining } }) => {
+  return (
+    <code className="bytes">
+      {bytes.map(({ value, provenance }, i) => (
+        <span
+          key={i}
+          className={provenance !== null ? "pointer" : undefined}
+          title={provenance !== null ? `#${provenance}` : undefined}
+        >
+          {value !== null ? value.toString(16).padStart(2, "0") : "__"}{" "}
+        </span>
+      ))}
+      {remaining > 0 ? <>… {remaining} more</> : null}
+    </code>
+  );
+};
+
 let Frame: React.FC<{ frame: MFrame }> = ({ frame }) => {
   return (
     <div className="frame">
-      {/* <code>{frame.name}</code>: */}
+      <code>{frame.name}</code>
       <table className="locals">
         <thead>
           <th>Name</th>
           <th>Value</th>
         </thead>
-        {frame.locals.map(([local, value], i) => (
+        {frame.locals.map(({ name, value, alloc_id, bytes }, i) => (
           <tr key={i}>
             <td>
-              <code>{local}</code>
+              <code>{name}</code>
+              {alloc_id !== null ? <> (#{alloc_id})</> : null}
+            </td>
+            <td>
+              <Value value={value} />
+              {bytes !== null ? <Bytes bytes={bytes} /> : null}
+            </td>
+          </tr>
+        ))}
+      </table>
+    </div>
+  );
+};
+
+/** Shows what the program has printed up to and including the given step. */
+let Output: React.FC<{ output: MOutput; step: number }> = ({ output, step }) => {
+  // The last step also shows what was written after it, e.g. on exit.
+  let isLast = step >= output.steps.length - 1;
+  let steps = output.steps.slice(0, step + 1);
+  let stdout = isLast ? output.stdout : steps.map(step => step.stdout).join("");
+  let stderr = isLast ? output.stderr : steps.map(step => step.stderr).join("");
+  return (
+    <div className="output">
+      {stdout.length > 0 ? <pre className="stdout">{stdout}</pre> : null}
+      {stderr.length > 0 ? <pre className="stderr">{stderr}</pre> : null}
+    </div>
+  );
+};
+
+let Termination: React.FC<{ termination: MTermination }> = ({ termination }) => {
+  if (termination.type == "Exit") {
+    return <div className="termination">Exited with code {termination.value.code}</div>;
+  } else if (termination.type == "Panic") {
+    return (
+      <div className="termination error">
+        Panicked: {termination.value.message}
+        {termination.value.stack.map(frame => (
+          <Frame key={frame.id} frame={frame} />
+        ))}
+      </div>
+    );
+  } else if (termination.type == "UndefinedBehavior") {
+    return (
+      <div className="termination error">Undefined behavior: {termination.value.message}</div>
+    );
+  } else if (termination.type == "ResourceExhaustion") {
+    return (
+      <div className="termination error">
+        Resource exhaustion: {termination.value.message}
+      </div>
+    );
+  } else if (termination.type == "Error") {
+    return <div className="termination error">Error: {termination.value.message}</div>;
+  } else if (termination.type == "LimitReached") {
+    let limit = termination.value.limit;
+    let reason =
+      limit == "Steps" ? "too many steps" : limit == "Frames" ? "trace too long" : "timed out";
+    return <div className="termination error">Stopped early: {reason}</div>;
+  } else {
+    throw `Unreachable`;
+  }
+};
+
+let Heap: React.FC<{ heap: MHeap }> = ({ heap }) => {
+  return (
+    <div className="heap">
+      <table className="locals">
+        <thead>
+          <th>Allocation</th>
+          <th>Value</th>
+        </thead>
+        {heap.allocations.map(({ id, kind, size, offset, value, bytes }) => (
+          <tr key={id}>
+            <td>
+              <code>#{id}</code> ({kind.toLowerCase()}, {size} bytes)
+              {offset > 0 ? <> from +{offset}</> : null}
             </td>
             <td>
               <Value value={value} />
+              {bytes !== null ? <Bytes bytes={bytes} /> : null}
             </td>
           </tr>
         ))}
@@ -115,7 +370,8 @@ export let Visualizer = observer(() => {
     client.addListener("VisOutput", message => {
       console.log(message.output);
       visualizer.output = message.output;
-      visualizer.step = 0;
+      // A program can stop before reaching its first step.
+      visualizer.step = message.output.steps.length > 0 ? 0 : -1;
     });
   }, []);
 
@@ -135,19 +391,43 @@ export let Visualizer = observer(() => {
             type="range"
             value={visualizer.step}
             min="0"
-            max={visualizer.output.length - 1}
+            max={visualizer.output.steps.length - 1}
             onChange={e => {
               visualizer.step = parseInt(e.target.value);
             }}
           />
           <button
             onClick={() => {
-              visualizer.step = Math.min(visualizer.step + 1, visualizer.output!.length - 1);
+              visualizer.step = Math.min(
+                visualizer.step + 1,
+                visualizer.output!.steps.length - 1
+              );
             }}
           >
             →
           </button>
-          <Frame frame={visualizer.output[visualizer.step]} />
+          {visualizer.step != -1 ? (
+            <>
+              <span className="reason">
+                {visualizer.output.steps[visualizer.step].reason.toLowerCase()}
+              </span>
+              {visualizer.output.steps[visualizer.step].stack.map(frame => (
+                <Frame key={frame.id} frame={frame} />
+              ))}
+              <Heap heap={visualizer.output.steps[visualizer.step].heap} />
+            </>
+          ) : null}
+          <Output output={visualizer.output} step={visualizer.step} />
+          {visualizer.step == visualizer.output.steps.length - 1 ? (
+            <Termination termination={visualizer.output.termination} />
+          ) : null}
+          {visualizer.output.warnings.length > 0 ? (
+            <ul className="warnings">
+              {visualizer.output.warnings.map((warning, i) => (
+                <li key={i}>{warning}</li>
+              ))}
+            </ul>
+          ) : null}
         </div>
       ) : null}
     </div>